@@ -5,6 +5,7 @@ use ethcore::open_state::CleanupMode;
 use ethcore::open_state::State;
 use ethcore::open_state_db::StateDB;
 use ethereum_types::U256;
+use parallel_evm::chain::Chain;
 use parallel_evm::execution_engine::sequential_exec;
 use parallel_evm::parallel_manager::ParallelManager;
 use parallel_evm::test_helpers;
@@ -47,15 +48,15 @@ fn bench_par_evm(b: &mut Bencher, input: &BenchInput, engines: usize) {
         parallel_manager.add_env_info(env_info);
         parallel_manager.add_transactions(input.transactions.clone());
         parallel_manager.clone_to_secure();
-        parallel_manager.consume();
-        parallel_manager.stop();
+        parallel_manager.consume().unwrap();
+        parallel_manager.stop().unwrap();
     });
 }
 
 fn bench_seq_evm(b: &mut Bencher, input: &BenchInput) {
     b.iter(|| {
         let mut state = input.state.clone();
-        sequential_exec(&mut state, &input.transactions);
+        sequential_exec(&mut state, &input.transactions, &Chain::default()).unwrap();
         state.commit().unwrap();
     });
 }
@@ -5,6 +5,7 @@ use ethcore::open_state::CleanupMode;
 use ethcore::open_state::State;
 use ethcore::open_state_db::StateDB;
 use ethereum_types::U256;
+use parallel_evm::chain::Chain;
 use parallel_evm::execution_engine::sequential_exec;
 use parallel_evm::parallel_manager::ParallelManager;
 use parallel_evm::test_helpers;
@@ -46,9 +47,9 @@ fn bench_par_evm(b: &mut Bencher, input: &BenchInput, engines: usize) {
             parallel_manager.add_env_info(env_info.clone());
             parallel_manager.add_transactions(txs.to_vec().clone());
             parallel_manager.clone_to_secure();
-            parallel_manager.consume();
-            parallel_manager.stop();
-            parallel_manager.apply_engines();
+            parallel_manager.consume().unwrap();
+            parallel_manager.stop().unwrap();
+            parallel_manager.apply_engines().unwrap();
             state = parallel_manager.drop();
         }
     });
@@ -58,7 +59,7 @@ fn bench_seq_evm(b: &mut Bencher, input: &BenchInput) {
     b.iter(|| {
         let mut state = input.state.clone();
         for txs in input.transactions.chunks(2000) {
-            sequential_exec(&mut state, &txs.to_vec());
+            sequential_exec(&mut state, &txs.to_vec(), &Chain::default()).unwrap();
             state.commit().unwrap();
         }
     });
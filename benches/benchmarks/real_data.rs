@@ -1,6 +1,4 @@
 extern crate parallel_evm;
-use common_types::block::Block;
-use common_types::transaction::SignedTransaction;
 use criterion::{Bencher, Criterion, Fun};
 use ethcore::ethereum;
 use ethcore::factory::Factories;
@@ -10,6 +8,7 @@ use ethcore::open_state_db::StateDB;
 use ethereum_types::{H256, U256};
 use parallel_evm::execution_engine::sequential_exec;
 use parallel_evm::parallel_manager::ParallelManager;
+use parallel_evm::preparation::{self, PreparedBlock};
 use parallel_evm::reward::Reward;
 use parallel_evm::test_helpers::{self, update_envinfo_by_header};
 use std::collections::VecDeque;
@@ -25,7 +24,7 @@ const N: usize = 1;
 
 struct BenchInput {
     state: State<StateDB>,
-    blocks: Vec<Block>,
+    blocks: Vec<PreparedBlock>,
     rewards: Vec<Reward>,
     last_hashes: Vec<H256>,
     parallel_managers: Vec<ParallelManager>,
@@ -49,20 +48,19 @@ fn bench_par_evm_3(b: &mut Bencher, input: &BenchInput) {
 
 fn bench_par_evm(b: &mut Bencher, input: &BenchInput, engines: usize) {
     let mut parallel_managers = input.parallel_managers.clone();
-    for pm in &mut parallel_managers {
-        pm.clone_to_secure();
-    }
     b.iter(|| {
         let mut state = input.state.clone();
         for parallel_manager in &mut parallel_managers {
             parallel_manager.set_state(state.clone());
             parallel_manager.add_engines(engines);
-            parallel_manager.consume();
-            let race = parallel_manager.stop();
+            // the secure engine takes the events of every run
+            parallel_manager.clone_to_secure();
+            parallel_manager.consume().unwrap();
+            let race = parallel_manager.stop().unwrap().race;
             if race {
-                parallel_manager.apply_secure();
+                parallel_manager.apply_selective().unwrap();
             } else {
-                parallel_manager.apply_engines();
+                parallel_manager.apply_engines().unwrap();
                 println!("no races");
             }
             state = parallel_manager.state();
@@ -78,9 +76,8 @@ fn bench_seq_evm(b: &mut Bencher, input: &BenchInput) {
         env_info.last_hashes = Arc::new(input.last_hashes.clone());
         for i in 0..N {
             update_envinfo_by_header(&mut env_info, &input.blocks[i].header);
-            for utx in &input.blocks[i].transactions {
-                let tx = SignedTransaction::new(utx.clone()).unwrap();
-                let outcome = state.apply(&env_info, &machine, &tx, true).unwrap();
+            for tx in &input.blocks[i].transactions {
+                let outcome = state.apply(&env_info, &machine, tx, true).unwrap();
                 env_info.gas_used = outcome.receipt.gas_used;
             }
 
@@ -114,7 +111,11 @@ fn bench(c: &mut Criterion) {
     let funs = vec![seq_evm, par_evm_1, par_evm_2, par_evm_3];
 
     let state_db = test_helpers::open_state_db(DB_PATH);
-    let blocks = test_helpers::read_blocks(BLOCK_PATH, 1, N);
+    let blocks: Vec<_> =
+        preparation::prepare_blocks(test_helpers::read_raw_blocks(BLOCK_PATH, 1, N), 4)
+            .into_iter()
+            .map(|block| block.unwrap())
+            .collect();
     let rewards = Reward::from_file(REWARD_PATH, 1, N);
     let mut last_hashes = VecDeque::from(test_helpers::load_last_hashes(LAST_HASHES_PATH));
     last_hashes.pop_front();
@@ -139,12 +140,8 @@ fn bench(c: &mut Criterion) {
         let reward = &rewards[i];
         let mut parallel_manager = ParallelManager::new(state.clone());
         update_envinfo_by_header(&mut env_info, &block.header);
-        let mut txs = vec![];
-        for utx in &block.transactions {
-            txs.push(SignedTransaction::new(utx.clone()).unwrap());
-        }
         parallel_manager.add_env_info(env_info.clone());
-        parallel_manager.add_transactions(txs.clone());
+        parallel_manager.add_prepared_block(block).unwrap();
         parallel_manager.add_reward(reward.clone());
         parallel_manager.clone_to_secure();
 
@@ -0,0 +1,561 @@
+use common_types::transaction::SignedTransaction;
+use ethcore::error::Error;
+use ethcore::machine::EthereumMachine;
+use ethcore::open_state::{ApplyOutcome, State};
+use ethcore::open_state_db::StateDB;
+use ethcore::trace::{RewardType, Tracer, VMTracer};
+use ethereum_types::{Address, H256, U256};
+use evm::Instruction;
+use hashbrown::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use vm::{ActionParams, ActionValue, EnvInfo, Error as VmError};
+
+/// Key of a single account or storage slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StateKey {
+    Account(Address),
+    Storage(Address, H256),
+}
+
+impl StateKey {
+    pub fn address(&self) -> Address {
+        match self {
+            StateKey::Account(addr) => *addr,
+            StateKey::Storage(addr, _) => *addr,
+        }
+    }
+}
+
+/// Accounts and storage slots read and written by a single execution event.
+///
+/// Account level writes cover balance, nonce, code and existence. Writing
+/// storage only counts as a read of the account, the slot itself is kept in
+/// `storage_writes`. Accounts which only receive balance increments, like
+/// the author collecting fees, are kept in `credits` with the amount: those
+/// commute, so several engines may credit the same account.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccessSet {
+    pub reads: HashSet<Address>,
+    pub writes: HashSet<Address>,
+    pub storage_reads: HashSet<(Address, H256)>,
+    pub storage_writes: HashSet<(Address, H256)>,
+    pub credits: HashMap<Address, U256>,
+}
+
+impl AccessSet {
+    /// Access set of a plain balance increment, e.g. block rewards.
+    pub fn add_balance(addr: Address, amount: U256) -> AccessSet {
+        let mut access_set = AccessSet::default();
+        access_set.credit(addr, amount);
+        access_set
+    }
+
+    pub fn read(&mut self, addr: Address) {
+        self.reads.insert(addr);
+    }
+
+    pub fn write(&mut self, addr: Address) {
+        self.writes.insert(addr);
+    }
+
+    /// Credits `amount` to `addr`, which is a plain write if the account
+    /// was otherwise touched.
+    pub fn credit(&mut self, addr: Address, amount: U256) {
+        if self.reads.contains(&addr) || self.writes.contains(&addr) {
+            self.writes.insert(addr);
+        } else {
+            *self.credits.entry(addr).or_insert_with(U256::zero) += amount;
+        }
+    }
+
+    pub fn read_storage(&mut self, addr: Address, key: H256) {
+        self.reads.insert(addr);
+        self.storage_reads.insert((addr, key));
+    }
+
+    pub fn write_storage(&mut self, addr: Address, key: H256) {
+        self.reads.insert(addr);
+        self.storage_writes.insert((addr, key));
+    }
+
+    /// Every account touched, whether read or written.
+    pub fn touched(&self) -> impl Iterator<Item = &Address> {
+        self.reads.iter().chain(self.writes.difference(&self.reads))
+    }
+
+    /// Every storage slot touched, whether read or written.
+    pub fn touched_storage(&self) -> impl Iterator<Item = &(Address, H256)> {
+        self.storage_reads
+            .iter()
+            .chain(self.storage_writes.difference(&self.storage_reads))
+    }
+
+    /// Every account and storage slot touched, as state keys.
+    pub fn touched_keys(&self) -> Vec<StateKey> {
+        self.touched()
+            .map(|addr| StateKey::Account(*addr))
+            .chain(
+                self.touched_storage()
+                    .map(|(addr, key)| StateKey::Storage(*addr, *key)),
+            )
+            .collect()
+    }
+
+    /// Every account and storage slot written, as state keys.
+    pub fn written_keys(&self) -> Vec<StateKey> {
+        self.writes
+            .iter()
+            .map(|addr| StateKey::Account(*addr))
+            .chain(
+                self.storage_writes
+                    .iter()
+                    .map(|(addr, key)| StateKey::Storage(*addr, *key)),
+            )
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct Frame {
+    stack: Vec<U256>,
+    // Instruction prepared but not yet reported as executed. CALL and
+    // CREATE families trap out of the interpreter and never report back.
+    pending: Option<Instruction>,
+}
+
+#[derive(Default)]
+struct Recorder {
+    access_set: AccessSet,
+    // storage addresses of the active call frames
+    addresses: Vec<Address>,
+    // mirrored EVM stacks of the active call frames
+    frames: Vec<Frame>,
+    // accounts called without value, which EIP-161 clears if empty
+    touched: HashSet<Address>,
+}
+
+/// Collects the exact account level read and write set of a transaction
+/// by hooking into the executive's tracers.
+///
+/// `State::apply_with_tracing` takes the call tracer and the vm tracer by
+/// value, so both share one recorder and the result is taken out of the
+/// `AccessRecorder` after the transaction is applied.
+#[derive(Clone, Default)]
+pub struct AccessRecorder {
+    inner: Arc<Mutex<Recorder>>,
+}
+
+impl AccessRecorder {
+    /// Recorder for `tx`, pre-filled with the accounts touched outside of
+    /// the EVM: the sender pays gas and bumps its nonce, and the author is
+    /// checked for existence if there is no fee to credit.
+    pub fn new(tx: &SignedTransaction, env_info: &EnvInfo) -> AccessRecorder {
+        let recorder = AccessRecorder::default();
+        {
+            let mut inner = recorder.inner.lock().unwrap();
+            inner.access_set.write(tx.sender());
+            if tx.gas_price.is_zero() {
+                inner.access_set.read(env_info.author);
+            }
+        }
+        recorder
+    }
+
+    pub fn tracer(&self) -> AccessTracer {
+        AccessTracer {
+            recorder: self.clone(),
+        }
+    }
+
+    pub fn vm_tracer(&self) -> AccessVMTracer {
+        AccessVMTracer {
+            recorder: self.clone(),
+        }
+    }
+
+    pub fn drain(&self) -> AccessSet {
+        let mut inner = self.inner.lock().unwrap();
+        inner.addresses.clear();
+        inner.frames.clear();
+        inner.touched.clear();
+        ::std::mem::replace(&mut inner.access_set, AccessSet::default())
+    }
+
+    fn touched(&self) -> Vec<Address> {
+        self.inner.lock().unwrap().touched.iter().cloned().collect()
+    }
+}
+
+/// Applies `tx` on `state`, recording its access set. The fee paid to the
+/// author is measured on the author's balance and recorded as a credit.
+///
+/// An account touched without value is only read, unless it is gone after
+/// the transaction: an empty account killed under EIP-161 is a write.
+/// Touched accounts which never existed are recorded as writes too, only
+/// the author is checked beforehand.
+pub fn apply_recorded(
+    state: &mut State<StateDB>,
+    env_info: &EnvInfo,
+    machine: &EthereumMachine,
+    tx: &SignedTransaction,
+) -> Result<(ApplyOutcome<(), ()>, AccessSet), Error> {
+    let recorder = AccessRecorder::new(tx, env_info);
+    let author_balance = state.balance(&env_info.author)?;
+    // the author is touched by a zero fee
+    let author_exists = tx.gas_price.is_zero() && state.exists(&env_info.author)?;
+    let outcome = state.apply_with_tracing(
+        env_info,
+        machine,
+        tx,
+        recorder.tracer(),
+        recorder.vm_tracer(),
+    )?;
+    let mut touched = recorder.touched();
+    if author_exists {
+        touched.push(env_info.author);
+    }
+    let mut access_set = recorder.drain();
+    for addr in touched {
+        if !state.exists(&addr)? {
+            access_set.write(addr);
+        }
+    }
+    if !tx.gas_price.is_zero() {
+        let fee = state
+            .balance(&env_info.author)?
+            .saturating_sub(author_balance);
+        access_set.credit(env_info.author, fee);
+    }
+    Ok((outcome, access_set))
+}
+
+/// Call tracer recording the accounts of every call, create and suicide.
+pub struct AccessTracer {
+    recorder: AccessRecorder,
+}
+
+impl Tracer for AccessTracer {
+    type Output = ();
+
+    fn prepare_trace_call(&mut self, params: &ActionParams, _depth: usize, _is_builtin: bool) {
+        let mut inner = self.recorder.inner.lock().unwrap();
+        inner.access_set.read(params.code_address);
+        inner.access_set.read(params.address);
+        if let ActionValue::Transfer(value) = params.value {
+            if value.is_zero() {
+                inner.touched.insert(params.address);
+            } else {
+                inner.access_set.write(params.sender);
+                inner.access_set.write(params.address);
+            }
+        }
+        inner.addresses.push(params.address);
+    }
+
+    fn prepare_trace_create(&mut self, params: &ActionParams) {
+        let mut inner = self.recorder.inner.lock().unwrap();
+        inner.access_set.write(params.sender);
+        inner.access_set.write(params.address);
+        inner.addresses.push(params.address);
+    }
+
+    fn done_trace_call(&mut self, _gas_used: U256, _output: &[u8]) {
+        self.recorder.inner.lock().unwrap().addresses.pop();
+    }
+
+    fn done_trace_create(&mut self, _gas_used: U256, _code: &[u8], _address: Address) {
+        self.recorder.inner.lock().unwrap().addresses.pop();
+    }
+
+    fn done_trace_failed(&mut self, _error: &VmError) {
+        self.recorder.inner.lock().unwrap().addresses.pop();
+    }
+
+    fn trace_suicide(&mut self, address: Address, _balance: U256, refund_address: Address) {
+        let mut inner = self.recorder.inner.lock().unwrap();
+        inner.access_set.write(address);
+        inner.access_set.write(refund_address);
+    }
+
+    fn trace_reward(&mut self, author: Address, value: U256, _reward_type: RewardType) {
+        self.recorder
+            .inner
+            .lock()
+            .unwrap()
+            .access_set
+            .credit(author, value);
+    }
+
+    fn drain(self) -> Vec<()> {
+        vec![]
+    }
+}
+
+/// VM tracer mirroring the EVM stack, so that the operands of account
+/// reading opcodes (BALANCE, EXTCODESIZE, EXTCODECOPY, EXTCODEHASH) and
+/// the keys of SLOAD can be recorded.
+pub struct AccessVMTracer {
+    recorder: AccessRecorder,
+}
+
+impl VMTracer for AccessVMTracer {
+    type Output = ();
+
+    fn trace_next_instruction(&mut self, _pc: usize, _instruction: u8, _current_gas: U256) -> bool {
+        true
+    }
+
+    fn trace_prepare_execute(
+        &mut self,
+        _pc: usize,
+        instruction: u8,
+        _gas_cost: U256,
+        _mem_written: Option<(usize, usize)>,
+        store_written: Option<(U256, U256)>,
+    ) {
+        let mut guard = self.recorder.inner.lock().unwrap();
+        let inner = &mut *guard;
+        if inner.frames.is_empty() {
+            inner.frames.push(Frame::default());
+        }
+        let address = inner.addresses.last().cloned();
+        let frame = inner.frames.last_mut().unwrap();
+
+        // The previous instruction trapped, its result was pushed by the
+        // interpreter on resume.
+        if let Some(trapped) = frame.pending.take() {
+            let info = trapped.info();
+            let len = frame.stack.len();
+            frame.stack.truncate(len.saturating_sub(info.args));
+            frame
+                .stack
+                .extend(::std::iter::repeat(U256::zero()).take(info.ret));
+        }
+
+        let instruction = match Instruction::from_u8(instruction) {
+            Some(instruction) => instruction,
+            None => return,
+        };
+        match instruction {
+            Instruction::BALANCE
+            | Instruction::EXTCODESIZE
+            | Instruction::EXTCODECOPY
+            | Instruction::EXTCODEHASH => {
+                if let Some(top) = frame.stack.last() {
+                    inner.access_set.read(Address::from(H256::from(*top)));
+                }
+            }
+            Instruction::SLOAD => {
+                if let (Some(address), Some(key)) = (address, frame.stack.last()) {
+                    inner.access_set.read_storage(address, H256::from(*key));
+                }
+            }
+            Instruction::SSTORE => {
+                if let (Some(address), Some((key, _))) = (address, store_written) {
+                    inner.access_set.write_storage(address, H256::from(key));
+                }
+            }
+            _ => (),
+        }
+        frame.pending = Some(instruction);
+    }
+
+    fn trace_failed(&mut self) {
+        let mut inner = self.recorder.inner.lock().unwrap();
+        if let Some(frame) = inner.frames.last_mut() {
+            frame.pending = None;
+        }
+    }
+
+    fn trace_executed(&mut self, _gas_used: U256, stack_push: &[U256], _mem: &[u8]) {
+        let mut inner = self.recorder.inner.lock().unwrap();
+        if let Some(frame) = inner.frames.last_mut() {
+            if let Some(executed) = frame.pending.take() {
+                let len = frame.stack.len();
+                frame
+                    .stack
+                    .truncate(len.saturating_sub(executed.info().args));
+                frame.stack.extend_from_slice(stack_push);
+            }
+        }
+    }
+
+    fn prepare_subtrace(&mut self, _code: &[u8]) {
+        self.recorder
+            .inner
+            .lock()
+            .unwrap()
+            .frames
+            .push(Frame::default());
+    }
+
+    fn done_subtrace(&mut self) {
+        self.recorder.inner.lock().unwrap().frames.pop();
+    }
+
+    fn drain(self) -> Option<()> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::Chain;
+    use crate::test_helpers;
+    use common_types::transaction::{Action, Transaction};
+    use ethcore::open_state::CleanupMode;
+    use ethstore::ethkey::{Generator, Random};
+
+    /// PUSH20 of `addr`.
+    fn push_address(addr: &Address) -> Vec<u8> {
+        let mut code = vec![0x73];
+        code.extend_from_slice(&addr[..]);
+        code
+    }
+
+    /// Access set of a call to `contract`, deployed with `code` along with
+    /// the other `contracts`.
+    fn trace(contract: Address, code: Vec<u8>, contracts: Vec<(Address, Vec<u8>)>) -> AccessSet {
+        let sender = Random.generate().unwrap();
+        let mut state = test_helpers::get_temp_state();
+        state
+            .add_balance(&sender.address(), &U256::from(1), CleanupMode::NoEmpty)
+            .unwrap();
+        state.init_code(&contract, code).unwrap();
+        for (addr, code) in contracts {
+            state.init_code(&addr, code).unwrap();
+        }
+        state.commit().unwrap();
+        let tx = Transaction {
+            action: Action::Call(contract),
+            value: U256::zero(),
+            data: vec![],
+            gas: U256::from(200_000),
+            gas_price: U256::zero(),
+            nonce: U256::zero(),
+        }
+        .sign(sender.secret(), None);
+
+        let chain = Chain::default();
+        let (_, access_set) =
+            apply_recorded(&mut state, &chain.env_info(), &chain.machine, &tx).unwrap();
+        assert!(access_set.writes.contains(&tx.sender()));
+        access_set
+    }
+
+    #[test]
+    fn test_account_reads() {
+        let contract = Address::random();
+        let targets: Vec<_> = (0..4).map(|_| Address::random()).collect();
+        let mut code = vec![];
+        // BALANCE, POP
+        code.extend(push_address(&targets[0]));
+        code.extend(vec![0x31, 0x50]);
+        // EXTCODESIZE, POP
+        code.extend(push_address(&targets[1]));
+        code.extend(vec![0x3b, 0x50]);
+        // EXTCODECOPY of no bytes
+        code.extend(vec![0x60, 0x00, 0x60, 0x00, 0x60, 0x00]);
+        code.extend(push_address(&targets[2]));
+        code.push(0x3c);
+        // EXTCODEHASH, POP, STOP
+        code.extend(push_address(&targets[3]));
+        code.extend(vec![0x3f, 0x50, 0x00]);
+
+        let access_set = trace(contract, code, vec![]);
+        assert!(access_set.reads.contains(&contract));
+        for target in &targets {
+            assert!(access_set.reads.contains(target));
+            assert!(!access_set.writes.contains(target));
+        }
+    }
+
+    #[test]
+    fn test_storage_accesses() {
+        let contract = Address::random();
+        // SLOAD 5, POP, SSTORE 1 at 7, STOP
+        let code = vec![0x60, 0x05, 0x54, 0x50, 0x60, 0x01, 0x60, 0x07, 0x55, 0x00];
+
+        let access_set = trace(contract, code, vec![]);
+        let slot = |key: u64| (contract, H256::from(key));
+        assert!(access_set.storage_reads.contains(&slot(5)));
+        assert!(!access_set.storage_reads.contains(&slot(7)));
+        assert!(access_set.storage_writes.contains(&slot(7)));
+        assert!(!access_set.storage_writes.contains(&slot(5)));
+        assert!(!access_set.writes.contains(&contract));
+    }
+
+    #[test]
+    fn test_nested_call() {
+        let (contract, callee) = (Address::random(), Address::random());
+        // SLOAD 3, POP, STOP
+        let callee_code = vec![0x60, 0x03, 0x54, 0x50, 0x00];
+        // CALL of the callee with no value nor data, with all the gas
+        let mut code = vec![0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00];
+        code.extend(push_address(&callee));
+        code.extend(vec![0x5a, 0xf1, 0x50]);
+        // back in the caller: SLOAD 1, POP, STOP
+        code.extend(vec![0x60, 0x01, 0x54, 0x50, 0x00]);
+
+        let access_set = trace(contract, code, vec![(callee, callee_code)]);
+        assert!(access_set.reads.contains(&callee));
+        // each slot is recorded on the account running the code
+        assert!(access_set.storage_reads.contains(&(callee, H256::from(3))));
+        assert!(access_set
+            .storage_reads
+            .contains(&(contract, H256::from(1))));
+        assert_eq!(access_set.storage_reads.len(), 2);
+        // a call without value writes nothing
+        assert!(!access_set.writes.contains(&callee));
+    }
+
+    #[test]
+    fn test_selfdestruct() {
+        let (contract, beneficiary) = (Address::random(), Address::random());
+        let mut code = push_address(&beneficiary);
+        code.push(0xff);
+
+        let access_set = trace(contract, code, vec![]);
+        assert!(access_set.writes.contains(&contract));
+        assert!(access_set.writes.contains(&beneficiary));
+    }
+
+    #[test]
+    fn test_touched_empty_account() {
+        let (contract, empty) = (Address::random(), Address::random());
+        // CALL of the empty account with no value nor data, STOP
+        let mut code = vec![0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00];
+        code.extend(push_address(&empty));
+        code.extend(vec![0x5a, 0xf1, 0x50, 0x00]);
+
+        let sender = Random.generate().unwrap();
+        let mut state = test_helpers::get_temp_state();
+        state
+            .add_balance(&sender.address(), &U256::from(1), CleanupMode::NoEmpty)
+            .unwrap();
+        state
+            .add_balance(&empty, &U256::zero(), CleanupMode::ForceCreate)
+            .unwrap();
+        state.init_code(&contract, code).unwrap();
+        state.commit().unwrap();
+        let tx = Transaction {
+            action: Action::Call(contract),
+            value: U256::zero(),
+            data: vec![],
+            gas: U256::from(200_000),
+            gas_price: U256::zero(),
+            nonce: U256::zero(),
+        }
+        .sign(sender.secret(), None);
+
+        let chain = Chain::default();
+        let (_, access_set) =
+            apply_recorded(&mut state, &chain.env_info(), &chain.machine, &tx).unwrap();
+        // killed by the call
+        assert!(!state.exists(&empty).unwrap());
+        assert!(access_set.writes.contains(&empty));
+        // the called contract survives the touch
+        assert!(!access_set.writes.contains(&contract));
+    }
+}
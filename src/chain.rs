@@ -0,0 +1,154 @@
+use ethcore::ethereum;
+use ethcore::machine::EthereumMachine;
+use ethcore::spec::Spec;
+use ethereum_types::U256;
+use std::fs::File;
+use std::sync::Arc;
+use vm::EnvInfo;
+
+const FOUNDATION: &[u8] = include_bytes!("../parity-ethereum/ethcore/res/ethereum/foundation.json");
+
+/// Machine and default block environment shared by all the engines, the
+/// secure engine and the sequential executor.
+///
+/// Fork transitions are part of the machine's params: the executive picks
+/// the schedule by `env_info.number`, so a machine loaded from a full chain
+/// spec switches forks by block number on its own.
+#[derive(Clone)]
+pub struct Chain {
+    pub machine: Arc<EthereumMachine>,
+    // gas limit of the environment used until a block's env info is set
+    pub gas_limit: U256,
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        Chain::from_machine(ethereum::new_constantinople_fix_test_machine())
+    }
+}
+
+impl Chain {
+    pub fn from_machine(machine: EthereumMachine) -> Chain {
+        Chain {
+            machine: Arc::new(machine),
+            gas_limit: U256::from(100_000_000),
+        }
+    }
+
+    /// Chain picked by name, either the mainnet spec or one of the test
+    /// machines with a single fork active from genesis.
+    pub fn from_name(name: &str) -> Result<Chain, String> {
+        let machine = match name {
+            "foundation" | "mainnet" => Spec::load_machine(FOUNDATION)?,
+            "frontier" => ethereum::new_frontier_test_machine(),
+            "homestead" => ethereum::new_homestead_test_machine(),
+            "byzantium" => ethereum::new_byzantium_test_machine(),
+            "constantinople" => ethereum::new_constantinople_test_machine(),
+            "constantinople_fix" => ethereum::new_constantinople_fix_test_machine(),
+            _ => return Err(format!("Unknown chain: {}", name)),
+        };
+        Ok(Chain::from_machine(machine))
+    }
+
+    /// Chain loaded from an ethjson spec file.
+    pub fn from_file(path: &str) -> Result<Chain, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        Ok(Chain::from_machine(Spec::load_machine(file)?))
+    }
+
+    pub fn with_gas_limit(mut self, gas_limit: U256) -> Chain {
+        self.gas_limit = gas_limit;
+        self
+    }
+
+    pub fn env_info(&self) -> EnvInfo {
+        let mut env_info = EnvInfo::default();
+        env_info.gas_limit = self.gas_limit;
+        env_info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parallel_manager::ParallelManager;
+    use crate::test_helpers;
+    use common_types::receipt::TransactionOutcome;
+
+    const BYZANTIUM: &str = "parity-ethereum/ethcore/res/ethereum/byzantium_test.json";
+
+    /// Applies the same block on the engines, on the secure engine alone
+    /// and sequentially under `chain`.
+    fn check_chain(chain: Chain) {
+        let transactions = test_helpers::static_dep_txs(20, 40, false);
+        let state = test_helpers::funded_state(&transactions, U256::from(1));
+        let expected = test_helpers::sequential_root(&state, &transactions, &chain);
+
+        let mut parallel_manager = ParallelManager::with_chain(state.clone(), chain.clone());
+        parallel_manager.add_engines(4);
+        parallel_manager.add_transactions(transactions.clone());
+        test_helpers::run_block(&mut parallel_manager).unwrap();
+        assert_eq!(&expected, parallel_manager.state_root());
+
+        let mut parallel_manager = ParallelManager::with_chain(state, chain);
+        parallel_manager.add_transactions(transactions);
+        parallel_manager.clone_to_secure();
+        parallel_manager.apply_secure().unwrap();
+        assert_eq!(&expected, parallel_manager.state_root());
+    }
+
+    #[test]
+    fn test_chain_from_name() {
+        check_chain(Chain::from_name("byzantium").unwrap());
+        assert!(Chain::from_name("ropsten").is_err());
+    }
+
+    #[test]
+    fn test_chain_from_file() {
+        check_chain(
+            Chain::from_file(BYZANTIUM)
+                .unwrap()
+                .with_gas_limit(U256::from(50_000_000)),
+        );
+        assert!(Chain::from_file("missing.json").is_err());
+    }
+
+    #[test]
+    fn test_mainnet_byzantium_transition() {
+        let chain = Chain::from_name("foundation").unwrap();
+        for (number, byzantium) in vec![(4_369_999, false), (4_370_000, true)] {
+            let transactions = test_helpers::static_dep_txs(20, 40, false);
+            let state = test_helpers::funded_state(&transactions, U256::from(1));
+            let mut env_info = chain.env_info();
+            env_info.number = number;
+
+            let mut expected = state.clone();
+            let mut sequential_env = env_info.clone();
+            let mut receipts = vec![];
+            for tx in &transactions {
+                let outcome = expected
+                    .apply(&sequential_env, &chain.machine, tx, false)
+                    .unwrap();
+                sequential_env.gas_used = outcome.receipt.gas_used;
+                receipts.push(outcome.receipt);
+            }
+            expected.commit().unwrap();
+
+            let mut parallel_manager = ParallelManager::with_chain(state, chain.clone());
+            parallel_manager.add_engines(4);
+            parallel_manager.add_env_info(env_info);
+            parallel_manager.add_transactions(transactions);
+            test_helpers::run_block(&mut parallel_manager).unwrap();
+            assert_eq!(expected.root(), parallel_manager.state_root());
+            assert_eq!(&receipts, parallel_manager.receipts());
+            // EIP-658 replaces the intermediate roots by a status code
+            for receipt in parallel_manager.receipts() {
+                match receipt.outcome {
+                    TransactionOutcome::StateRoot(_) => assert!(!byzantium),
+                    TransactionOutcome::StatusCode(_) => assert!(byzantium),
+                    TransactionOutcome::Unknown => panic!("receipts carry an outcome"),
+                }
+            }
+        }
+    }
+}
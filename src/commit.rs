@@ -0,0 +1,270 @@
+use crate::error::Error;
+use ethcore::factory::Factories;
+use ethcore::open_state::State;
+use ethcore::open_state_db::StateDB;
+use ethereum_types::{Address, H256};
+use hash_db::{HashDB, Hasher};
+use hashbrown::{HashMap, HashSet};
+use keccak_hasher::KeccakHasher;
+use kvdb::DBValue;
+use memory_db::MemoryDB;
+use rlp::{DecoderError, Rlp, NULL_RLP};
+use std::thread;
+use trie_db::Trie;
+
+/// Commits states on top of the state at `root`, which may only change
+/// disjoint accounts, to the same root as committing them one by one.
+///
+/// Each state is committed on its own thread, into its own copy of the
+/// database, so that the storage tries and the code are hashed in
+/// parallel. A state's accounts go into a trie of their own, started from
+/// the empty trie, which only serves to read their leaves back. The
+/// reference count changes of every other node, insertions and removals
+/// alike, are copied into `state_db`, and the leaves of the `accounts`
+/// which changed are folded into the account trie at `root`, which is
+/// thus only hashed once.
+pub fn commit_parallel(
+    state_db: &mut StateDB,
+    root: &mut H256,
+    factories: &Factories,
+    states: Vec<(State<StateDB>, HashSet<Address>)>,
+) -> Result<(), Error> {
+    let base = root.clone();
+    // Every copy starts with the nodes not journaled yet, which are
+    // already in `state_db` and must not be counted again.
+    let parent = state_db
+        .boxed_clone()
+        .journal_db_mut()
+        .drain_transaction_overlay()
+        .map_err(|e| Error::Commit(e.into()))?;
+    let handlers: Vec<_> = states
+        .into_iter()
+        .enumerate()
+        .map(|(number, (state, accounts))| {
+            let db = state_db.boxed_clone();
+            let factories = factories.clone();
+            thread::Builder::new()
+                .name(format!("{}{}", "commit".to_string(), &number.to_string()))
+                .spawn(move || commit_changes(state, db, &factories, &base, accounts))
+                .unwrap()
+        })
+        .collect();
+
+    let mut leaves = vec![];
+    for handler in handlers {
+        let died = Error::EngineDied(handler.thread().name().unwrap_or("commit").to_string());
+        let (mut overlay, changes_nodes, changed) = handler.join().map_err(|_| died)??;
+        for (key, (value, rc)) in overlay.drain() {
+            let delta = rc
+                - parent.raw(&key).map_or(0, |(_, rc)| rc)
+                - changes_nodes.get(&key).cloned().unwrap_or(0);
+            if delta > 0 {
+                for _ in 0..delta {
+                    state_db.as_hash_db_mut().emplace(key, value.clone());
+                }
+            } else {
+                for _ in 0..-delta {
+                    state_db.as_hash_db_mut().remove(&key);
+                }
+            }
+        }
+        leaves.extend(changed);
+    }
+
+    let mut trie = factories
+        .trie
+        .from_existing(state_db.as_hash_db_mut(), root)
+        .map_err(|e| Error::Commit(e.into()))?;
+    for (addr, leaf) in leaves {
+        match leaf {
+            Some(leaf) => trie.insert(&addr, &leaf),
+            None => trie.remove(&addr),
+        }
+        .map_err(|e| Error::Commit(e.into()))?;
+    }
+    Ok(())
+}
+
+/// (account, leaf, `None` if deleted) of the accounts a state changed.
+type Leaves = Vec<(Address, Option<DBValue>)>;
+
+/// Nodes a state inserted and removed, the nodes of its changes trie with
+/// their number of occurrences, and the leaves of the accounts it changed.
+type Committed = (MemoryDB<KeccakHasher, DBValue>, HashMap<H256, i32>, Leaves);
+
+/// Commits a state into its copy of the database.
+fn commit_changes(
+    mut state: State<StateDB>,
+    mut db: StateDB,
+    factories: &Factories,
+    base: &H256,
+    accounts: HashSet<Address>,
+) -> Result<Committed, Error> {
+    let mut changes_root = KeccakHasher::hash(&NULL_RLP);
+    state
+        .commit_external(&mut db, &mut changes_root, true)
+        .map_err(|e| Error::Commit(e.into()))?;
+
+    let mut changed = vec![];
+    let mut changes_nodes = HashMap::new();
+    {
+        let changes = factories
+            .trie
+            .readonly(db.as_hash_db(), &changes_root)
+            .map_err(|e| Error::Commit(e.into()))?;
+        let base_trie = factories
+            .trie
+            .readonly(db.as_hash_db(), base)
+            .map_err(|e| Error::Commit(e.into()))?;
+        for addr in accounts {
+            let leaf = match changes.get(&addr).map_err(|e| Error::Commit(e.into()))? {
+                Some(leaf) => Some(leaf),
+                // only committed accounts are in the changes trie
+                None if state.exists(&addr).map_err(|e| Error::Commit(e.into()))? => continue,
+                None => None,
+            };
+            let base_leaf = base_trie.get(&addr).map_err(|e| Error::Commit(e.into()))?;
+            if leaf != base_leaf {
+                changed.push((addr, leaf));
+            }
+        }
+        trie_nodes(db.as_hash_db(), &changes_root, &mut changes_nodes)
+            .map_err(|e| Error::Commit(e.into()))?;
+    }
+    let overlay = db
+        .journal_db_mut()
+        .drain_transaction_overlay()
+        .map_err(|e| Error::Commit(e.into()))?;
+    Ok((overlay, changes_nodes, changed))
+}
+
+/// Counts the hashed nodes of the trie at `root`.
+fn trie_nodes(
+    db: &dyn HashDB<KeccakHasher, DBValue>,
+    root: &H256,
+    nodes: &mut HashMap<H256, i32>,
+) -> Result<(), DecoderError> {
+    // the empty trie is not stored
+    if let Some(node) = db.get(root) {
+        if node[..] != NULL_RLP[..] {
+            *nodes.entry(*root).or_insert(0) += 1;
+            child_nodes(db, &Rlp::new(&node), nodes)?;
+        }
+    }
+    Ok(())
+}
+
+fn child_nodes(
+    db: &dyn HashDB<KeccakHasher, DBValue>,
+    node: &Rlp,
+    nodes: &mut HashMap<H256, i32>,
+) -> Result<(), DecoderError> {
+    let children = match node.item_count()? {
+        // branch, its value is never a node
+        17 => (0..16).collect(),
+        // extension, a leaf has the terminator flag in its path
+        2 if node.at(0)?.data()?.first().map_or(false, |b| b & 0x20 == 0) => vec![1],
+        _ => vec![],
+    };
+    for i in children {
+        let child = node.at(i)?;
+        if child.is_list() {
+            // inlined, too short to be hashed
+            child_nodes(db, &child, nodes)?;
+        } else if child.size() == 32 {
+            trie_nodes(db, &H256::from_slice(child.data()?), nodes)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers;
+    use ethcore::open_state::CleanupMode;
+    use ethereum_types::U256;
+
+    #[test]
+    fn test_parallel_commit() {
+        let mut state = test_helpers::get_temp_state();
+        let (a, b, token) = (Address::from(1), Address::from(2), Address::from(3));
+        state
+            .add_balance(&a, &U256::from(10), CleanupMode::NoEmpty)
+            .unwrap();
+        state
+            .set_storage(&token, H256::from(1), H256::from(5))
+            .unwrap();
+        state.commit().unwrap();
+        let (base, state_db) = state.drop();
+        let open = |state_db: &StateDB| {
+            State::from_existing(
+                state_db.boxed_clone(),
+                base,
+                U256::zero(),
+                Factories::default(),
+            )
+            .unwrap()
+        };
+
+        // disjoint changes on two states, the first replacing the nodes of
+        // a storage trie
+        let mut first = open(&state_db);
+        first
+            .sub_balance(&a, &U256::from(5), &mut CleanupMode::NoEmpty)
+            .unwrap();
+        first
+            .set_storage(&token, H256::from(1), H256::from(7))
+            .unwrap();
+        let mut second = open(&state_db);
+        second
+            .add_balance(&b, &U256::from(5), CleanupMode::NoEmpty)
+            .unwrap();
+        let accounts = |addrs: &[Address]| addrs.iter().cloned().collect::<HashSet<_>>();
+        // `a` unchanged by the second state is skipped
+        let states = vec![
+            (first.clone(), accounts(&[a, token])),
+            (second.clone(), accounts(&[a, b])),
+        ];
+
+        let mut expected_db = state_db.boxed_clone();
+        let mut expected_root = base;
+        for mut state in vec![first, second] {
+            state
+                .commit_external(&mut expected_db, &mut expected_root, true)
+                .unwrap();
+        }
+
+        let mut committed_db = state_db.boxed_clone();
+        let mut root = base;
+        commit_parallel(&mut committed_db, &mut root, &Factories::default(), states).unwrap();
+        assert_eq!(root, expected_root);
+
+        // the same nodes referenced as often, removals included
+        let nodes = |state_db: &mut StateDB| {
+            let mut overlay = state_db
+                .journal_db_mut()
+                .drain_transaction_overlay()
+                .unwrap();
+            let mut nodes: Vec<_> = overlay
+                .drain()
+                .into_iter()
+                .filter(|(_, (_, rc))| *rc != 0)
+                .map(|(key, (_, rc))| (key, rc))
+                .collect();
+            nodes.sort();
+            nodes
+        };
+        assert_eq!(
+            nodes(&mut committed_db.boxed_clone()),
+            nodes(&mut expected_db)
+        );
+
+        let state =
+            State::from_existing(committed_db, root, U256::zero(), Factories::default()).unwrap();
+        assert_eq!(
+            state.storage_at(&token, &H256::from(1)).unwrap(),
+            H256::from(7)
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use crossbeam_channel::{RecvError, SendError};
+use ethcore::error::Error as EthcoreError;
+use ethereum_types::Address;
+use ethstore::ethkey;
+use std::error::Error as StdError;
+use std::fmt;
+
+/// Errors of parallel execution.
+#[derive(Debug)]
+pub enum Error {
+    /// The transaction at the given position in the block failed to
+    /// execute, e.g. an invalid transaction.
+    Execution(usize, EthcoreError),
+    /// Crediting the block or uncle reward to the account failed.
+    Reward(Address, EthcoreError),
+    /// The sender of the transaction at the given position in the block
+    /// could not be recovered.
+    InvalidSignature(usize, ethkey::Error),
+    /// An engine thread panicked or exited, by thread name.
+    EngineDied(String),
+    /// An engine gave up waiting for the cache of an account migrated from
+    /// another engine, by thread name.
+    Stalled(String, Address),
+    /// An engine did not report back in time, by thread name.
+    Timeout(String),
+    /// A channel to or from an engine was disconnected.
+    Disconnected,
+    /// Committing a state to the database failed.
+    Commit(EthcoreError),
+    /// The engines' result can't be used and the secure engine is disabled.
+    SecureDisabled,
+    /// The secure engine was joined without a job, see
+    /// `ParallelManager::clone_to_secure`.
+    SecureIdle,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Execution(index, e) => write!(f, "Transaction {} failed: {}", index, e),
+            Error::Reward(addr, e) => write!(f, "Reward of {:?} failed: {}", addr, e),
+            Error::InvalidSignature(index, e) => {
+                write!(f, "Transaction {} has an invalid signature: {}", index, e)
+            }
+            Error::EngineDied(name) => write!(f, "Engine {} died", name),
+            Error::Stalled(name, addr) => {
+                write!(f, "Engine {} stalled waiting for {:?}", name, addr)
+            }
+            Error::Timeout(name) => write!(f, "Engine {} timed out", name),
+            Error::Disconnected => write!(f, "Engine channel disconnected"),
+            Error::Commit(e) => write!(f, "Commit failed: {}", e),
+            Error::SecureDisabled => write!(f, "Secure engine disabled"),
+            Error::SecureIdle => write!(f, "Secure engine has no job"),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+impl<T> From<SendError<T>> for Error {
+    fn from(_: SendError<T>) -> Error {
+        Error::Disconnected
+    }
+}
+
+impl From<RecvError> for Error {
+    fn from(_: RecvError) -> Error {
+        Error::Disconnected
+    }
+}
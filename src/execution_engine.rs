@@ -1,101 +1,204 @@
+use crate::access_tracer::{apply_recorded, AccessSet};
+use crate::chain::Chain;
+use crate::error::Error;
+use crate::receipts::own_gas_receipt;
+use crate::shared_cache::{transaction_accounts, BlockCache};
+use common_types::receipt::Receipt;
 use common_types::transaction::SignedTransaction;
-use crossbeam_channel::{self, unbounded, Sender};
-use ethcore::ethereum::new_constantinople_fix_test_machine as machine_generator;
+use crossbeam_channel::{self, unbounded, Receiver, RecvTimeoutError, SendError, Sender};
+use ethcore::error::Error as EthcoreError;
 use ethcore::open_state::{AccountEntry, CleanupMode, State};
 use ethcore::open_state_db::StateDB;
-use ethcore::trace::trace::{Action, Res};
 use ethereum_types::{Address, U256};
+use hashbrown::HashSet;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Weak};
 use std::thread::{self, JoinHandle};
+use std::time::Duration;
 use vm::EnvInfo;
 
+/// How long an engine waits for a migrated account, and the manager for an
+/// engine to stop, before giving up on the parallel execution of a block.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Clone)]
 pub enum ExecutionEvent {
     Stop,
+    Reset(State<StateDB>),
+    Shutdown,
     Transact(SignedTransaction),
     ChangeEnv(EnvInfo),
-    SendCache(Address, Sender<(Address, AccountEntry)>),
-    WaitCache(Address),
+    /// Drops the accounts and sends their caches, storage and code
+    /// included, to another engine.
+    SendCache(Vec<Address>, Sender<(Address, AccountEntry)>),
+    /// Waits for the caches of all the accounts before going on.
+    WaitCache(Vec<Address>),
     AddBalance(Address, U256),
+    /// Reads the accounts of the next transactions, until the next reset,
+    /// through the shared cache.
+    Warm(BlockCache),
 }
 
+/// Access set of an executed event and the receipt of a transaction, or
+/// the error the event failed with.
+pub type Executed = Result<(AccessSet, Option<Receipt>), EthcoreError>;
+
+/// Engine worker, kept alive across blocks. After `stop` the engine waits
+/// to be `reset` with the state of the next block, or `shutdown`.
 pub struct ExecutionEngine {
     execution_channel_tx: Sender<ExecutionEvent>,
     cache_channel_tx: Sender<(Address, AccountEntry)>,
-    handler: JoinHandle<(State<StateDB>, Vec<Address>)>,
+    result_channel_rx: Receiver<Result<(State<StateDB>, Vec<Executed>), Error>>,
+    // access set of every executed event as it runs, `None` if it failed
+    access_channel_rx: Receiver<Option<AccessSet>>,
+    aborted: Arc<AtomicBool>,
+    handler: JoinHandle<()>,
+}
+
+struct SecureJob {
+    state: State<StateDB>,
+    cache: Option<BlockCache>,
+    events: Vec<ExecutionEvent>,
+    running: Arc<AtomicBool>,
 }
 
+/// Sequential fallback, running on one worker thread kept alive across
+/// blocks until `shutdown`.
 pub struct SecureEngine {
     state: State<StateDB>,
-    handler: Option<JoinHandle<State<StateDB>>>,
+    cache: Option<BlockCache>,
+    chain: Chain,
+    job_channel_tx: Option<Sender<SecureJob>>,
+    result_channel_rx: Receiver<Result<(State<StateDB>, Vec<Receipt>), Error>>,
+    handler: Option<JoinHandle<()>>,
     running: Option<Weak<AtomicBool>>,
     execution_events: Option<Vec<ExecutionEvent>>,
 }
 
 impl ExecutionEngine {
-    pub fn start(mut state: State<StateDB>, number: usize) -> ExecutionEngine {
+    /// Starts an engine on `state`. An engine waiting longer than `timeout`
+    /// for a migrated account stalls: it skips the rest of the block and
+    /// reports `Error::Stalled` on `stop`.
+    pub fn start(
+        mut state: State<StateDB>,
+        number: usize,
+        chain: Chain,
+        timeout: Duration,
+    ) -> ExecutionEngine {
         let (execution_channel_tx, execution_channel_rx) = unbounded();
         let (cache_channel_tx, cache_channel_rx) = unbounded();
-        let mut env_info = EnvInfo::default();
-        let machine = machine_generator();
-        env_info.gas_limit = U256::from(100_000_000);
+        let (result_channel_tx, result_channel_rx) = unbounded();
+        let (access_channel_tx, access_channel_rx) = unbounded();
+        let aborted = Arc::new(AtomicBool::new(false));
+        let abort = aborted.clone();
+        let machine = chain.machine.clone();
+        let name = format!("{}{}", "engine".to_string(), &number.to_string());
 
         let handler = thread::Builder::new()
-            .name(format!("{}{}", "engine".to_string(), &number.to_string()))
-            .spawn(move || {
+            .name(name.clone())
+            .spawn(move || loop {
+                let mut env_info = chain.env_info();
                 let mut cache_buffer = vec![];
-                let mut internal_call_addr = vec![];
+                let mut executed = vec![];
+                let mut stalled = None;
+                let mut block_cache: Option<BlockCache> = None;
+                // accounts the state may hold changes of
+                let mut touched = HashSet::new();
                 loop {
-                    match execution_channel_rx.recv().unwrap() {
+                    let event = match execution_channel_rx.recv() {
+                        Ok(event) => event,
+                        Err(_) => return,
+                    };
+                    if let Some(ref cache) = block_cache {
+                        let addrs: Vec<_> = transaction_accounts(&event)
+                            .into_iter()
+                            .filter(|addr| touched.insert(*addr))
+                            .collect();
+                        cache.warm(&mut state, &addrs);
+                    }
+                    match event {
                         ExecutionEvent::Stop => {
                             break;
                         }
-                        ExecutionEvent::Transact(tx) => {
-                            let outcome = state.apply(&env_info, &machine, &tx, true).unwrap();
-                            let trace = outcome.trace;
-                            // TODO: check CALL
-                            // the transaction has internal call
-                            for sub_trace in &trace[1..] {
-                                match &sub_trace.action {
-                                    Action::Call(call) => {
-                                        if !internal_call_addr.contains(&call.to) {
-                                            internal_call_addr.push(call.to);
-                                        }
-                                    }
-                                    Action::Create(_) => match &sub_trace.result {
-                                        Res::Create(create) => {
-                                            if !internal_call_addr.contains(&create.address) {
-                                                internal_call_addr.push(create.address);
-                                            }
-                                        }
-                                        _ => (),
-                                    },
-                                    _ => (),
-                                }
+                        ExecutionEvent::Reset(new_state) => {
+                            state = new_state;
+                            env_info = chain.env_info();
+                            // caches sent for waits skipped by an abort or
+                            // a stall belong to the old block
+                            while cache_channel_rx.try_recv().is_ok() {}
+                            cache_buffer.clear();
+                            executed.clear();
+                            stalled = None;
+                            block_cache = None;
+                            touched.clear();
+                        }
+                        ExecutionEvent::Warm(cache) => block_cache = Some(cache),
+                        ExecutionEvent::Shutdown => return,
+                        // Keeps serving migrations, so that other engines
+                        // don't stall on this one too.
+                        ExecutionEvent::SendCache(addrs, cache_channel_tx) => {
+                            for addr in addrs {
+                                let account_entry = state.drop_account(&addr);
+                                // the receiving engine may have been dropped
+                                // after stalling
+                                let _ = cache_channel_tx.send((addr, account_entry));
                             }
                         }
-                        ExecutionEvent::SendCache(addr, cache_channel_tx) => {
-                            let account_entry = state.drop_account(&addr);
-                            cache_channel_tx.send((addr, account_entry)).unwrap();
+                        _ if stalled.is_some() || abort.load(Ordering::SeqCst) => (),
+                        ExecutionEvent::Transact(tx) => {
+                            // A failed transaction leaves the state untouched
+                            // and is reported to the manager.
+                            let result = apply_recorded(&mut state, &env_info, &machine, &tx).map(
+                                |(outcome, access_set)| {
+                                    // Only counts the gas of this engine's own
+                                    // transactions, the block position is
+                                    // restored by the manager.
+                                    let gas_used = outcome.receipt.gas_used;
+                                    let receipt = own_gas_receipt(outcome.receipt, &env_info);
+                                    env_info.gas_used = gas_used;
+                                    (access_set, Some(receipt))
+                                },
+                            );
+                            if let Ok((ref access_set, _)) = result {
+                                touched.extend(access_set.touched().cloned());
+                                touched.extend(access_set.credits.keys().cloned());
+                            }
+                            let _ = access_channel_tx.send(
+                                result
+                                    .as_ref()
+                                    .ok()
+                                    .map(|(access_set, _)| access_set.clone()),
+                            );
+                            executed.push(result);
                         }
-                        ExecutionEvent::WaitCache(addr) => {
-                            let mut cached = false;
-                            let len = cache_buffer.len();
-                            for i in 0..len {
-                                if cache_buffer[i] == addr {
-                                    cache_buffer.remove(i);
-                                    cached = true;
-                                    break;
+                        ExecutionEvent::WaitCache(mut addrs) => {
+                            // caches which arrived ahead of their wait
+                            addrs.retain(|addr| {
+                                match cache_buffer.iter().position(|a| a == addr) {
+                                    Some(i) => {
+                                        cache_buffer.remove(i);
+                                        false
+                                    }
+                                    None => true,
                                 }
-                            }
-                            while !cached {
-                                let (_addr, account_entry) = cache_channel_rx.recv().unwrap();
+                            });
+                            while !addrs.is_empty() {
+                                let (_addr, account_entry) =
+                                    match cache_channel_rx.recv_timeout(timeout) {
+                                        Ok(cache) => cache,
+                                        Err(RecvTimeoutError::Timeout) => {
+                                            stalled = Some(addrs[0]);
+                                            break;
+                                        }
+                                        Err(RecvTimeoutError::Disconnected) => return,
+                                    };
                                 state.insert_cache(&_addr, account_entry);
-                                if _addr == addr {
-                                    cached = true;
-                                } else {
-                                    cache_buffer.push(_addr);
+                                touched.insert(_addr);
+                                match addrs.iter().position(|addr| *addr == _addr) {
+                                    Some(i) => {
+                                        addrs.remove(i);
+                                    }
+                                    None => cache_buffer.push(_addr),
                                 }
                             }
                         }
@@ -103,59 +206,158 @@ impl ExecutionEngine {
                             env_info = new_env_info;
                         }
                         ExecutionEvent::AddBalance(addr, amount) => {
-                            state
+                            let result = state
                                 .add_balance(&addr, &amount, CleanupMode::NoEmpty)
-                                .unwrap();
+                                .map(|_| (AccessSet::add_balance(addr, amount), None))
+                                .map_err(EthcoreError::from);
+                            let _ = access_channel_tx.send(
+                                result
+                                    .as_ref()
+                                    .ok()
+                                    .map(|(access_set, _)| access_set.clone()),
+                            );
+                            executed.push(result);
                         }
                     }
                 }
-                (state, internal_call_addr)
+                let result = match stalled {
+                    Some(addr) => Err(Error::Stalled(name.clone(), addr)),
+                    None => Ok((state, executed)),
+                };
+                if result_channel_tx.send(result).is_err() {
+                    return;
+                }
+                // idle until the next block
+                match execution_channel_rx.recv() {
+                    Ok(ExecutionEvent::Reset(new_state)) => {
+                        state = new_state;
+                        while cache_channel_rx.try_recv().is_ok() {}
+                    }
+                    _ => return,
+                }
             })
             .unwrap();
         let execution_engine = ExecutionEngine {
             execution_channel_tx: execution_channel_tx,
             cache_channel_tx: cache_channel_tx,
+            result_channel_rx: result_channel_rx,
+            access_channel_rx: access_channel_rx,
+            aborted: aborted,
             handler: handler,
         };
 
         return execution_engine;
     }
 
-    pub fn push_transaction(&self, tx: SignedTransaction) {
+    pub fn push_transaction(&self, tx: SignedTransaction) -> Result<(), Error> {
         self.execution_channel_tx
-            .send(ExecutionEvent::Transact(tx))
-            .unwrap();
+            .send(ExecutionEvent::Transact(tx))?;
+        Ok(())
     }
 
-    pub fn push_add_balance(&self, addr: Address, amount: U256) {
+    pub fn push_add_balance(&self, addr: Address, amount: U256) -> Result<(), Error> {
         self.execution_channel_tx
-            .send(ExecutionEvent::AddBalance(addr, amount))
-            .unwrap();
+            .send(ExecutionEvent::AddBalance(addr, amount))?;
+        Ok(())
     }
 
-    pub fn push_env(&self, env_info: EnvInfo) {
+    pub fn push_env(&self, env_info: EnvInfo) -> Result<(), Error> {
         self.execution_channel_tx
-            .send(ExecutionEvent::ChangeEnv(env_info))
-            .unwrap();
+            .send(ExecutionEvent::ChangeEnv(env_info))?;
+        Ok(())
     }
 
-    pub fn send_cache(&self, addr: Address, channel_tx: Sender<(Address, AccountEntry)>) {
+    pub fn send_cache(
+        &self,
+        addrs: Vec<Address>,
+        channel_tx: Sender<(Address, AccountEntry)>,
+    ) -> Result<(), Error> {
         self.execution_channel_tx
-            .send(ExecutionEvent::SendCache(addr, channel_tx))
-            .unwrap();
+            .send(ExecutionEvent::SendCache(addrs, channel_tx))?;
+        Ok(())
     }
 
-    pub fn wait_cache(&self, addr: Address) {
+    pub fn wait_cache(&self, addrs: Vec<Address>) -> Result<(), Error> {
         self.execution_channel_tx
-            .send(ExecutionEvent::WaitCache(addr))
-            .unwrap();
+            .send(ExecutionEvent::WaitCache(addrs))?;
+        Ok(())
+    }
+
+    /// Makes the engine read the accounts of its transactions through the
+    /// cache for the rest of the block.
+    pub fn warm(&self, cache: BlockCache) -> Result<(), Error> {
+        self.execution_channel_tx
+            .send(ExecutionEvent::Warm(cache))?;
+        Ok(())
     }
 
-    pub fn stop(self) -> (State<StateDB>, Vec<Address>) {
+    /// Stops the engine, returning its state and, in execution order, the
+    /// access set of every transaction and balance increment it executed
+    /// along with the receipts of the transactions, or their errors. Gives
+    /// up on an engine which executes no event within `timeout`, and aborts
+    /// the rest of its block so that it doesn't keep running once dropped.
+    pub fn stop(&self, timeout: Duration) -> Result<(State<StateDB>, Vec<Executed>), Error> {
         self.execution_channel_tx
             .send(ExecutionEvent::Stop)
-            .unwrap();
-        self.handler.join().unwrap()
+            .map_err(|_| self.died())?;
+        // access sets nobody waits for pile up until the next reset
+        let mut progress = self.access_channel_rx.len();
+        loop {
+            match self.result_channel_rx.recv_timeout(timeout) {
+                Ok(result) => return result,
+                Err(RecvTimeoutError::Timeout) => {
+                    let accesses = self.access_channel_rx.len();
+                    if accesses == progress {
+                        self.abort();
+                        return Err(Error::Timeout(self.name()));
+                    }
+                    progress = accesses;
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(self.died()),
+            }
+        }
+    }
+
+    /// Waits for the access set of the next event the engine executes,
+    /// `None` if the event failed.
+    pub fn next_access(&self, timeout: Duration) -> Result<Option<AccessSet>, Error> {
+        match self.access_channel_rx.recv_timeout(timeout) {
+            Ok(access_set) => Ok(access_set),
+            Err(RecvTimeoutError::Timeout) => Err(Error::Timeout(self.name())),
+            Err(RecvTimeoutError::Disconnected) => Err(self.died()),
+        }
+    }
+
+    /// Makes the engine skip the rest of the block, the events executed so
+    /// far are still reported on `stop`.
+    pub fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+    }
+
+    /// Restarts a stopped engine on the state of the next block.
+    pub fn reset(&self, state: State<StateDB>) -> Result<(), Error> {
+        self.aborted.store(false, Ordering::SeqCst);
+        // accesses of the last block nobody waited for
+        while self.access_channel_rx.try_recv().is_ok() {}
+        self.execution_channel_tx
+            .send(ExecutionEvent::Reset(state))
+            .map_err(|_| self.died())
+    }
+
+    /// Terminates a stopped engine.
+    pub fn shutdown(self) -> Result<(), Error> {
+        let died = self.died();
+        // an engine which already exited can't receive it
+        let _ = self.execution_channel_tx.send(ExecutionEvent::Shutdown);
+        self.handler.join().map_err(|_| died)
+    }
+
+    fn name(&self) -> String {
+        self.handler.thread().name().unwrap_or("engine").to_string()
+    }
+
+    fn died(&self) -> Error {
+        Error::EngineDied(self.name())
     }
 
     pub fn cache_channel_tx(&self) -> Sender<(Address, AccountEntry)> {
@@ -164,77 +366,318 @@ impl ExecutionEngine {
 }
 
 impl SecureEngine {
-    pub fn new(state: State<StateDB>) -> SecureEngine {
+    pub fn new(state: State<StateDB>, chain: Chain) -> SecureEngine {
+        let (job_channel_tx, result_channel_rx, handler) = spawn_secure(chain.clone());
         SecureEngine {
             state: state,
-            handler: None,
+            cache: None,
+            chain: chain,
+            job_channel_tx: Some(job_channel_tx),
+            result_channel_rx: result_channel_rx,
+            handler: Some(handler),
             running: None,
             execution_events: None,
         }
     }
 
-    pub fn run(&mut self) {
+    pub fn set_state(&mut self, state: State<StateDB>) {
+        self.state = state;
+    }
+
+    /// Cache the accounts of the next jobs are read through, it has to be
+    /// at the root of the state.
+    pub fn set_cache(&mut self, cache: BlockCache) {
+        self.cache = Some(cache);
+    }
+
+    pub fn run(&mut self) -> Result<(), Error> {
         if let Some(events) = self.execution_events.take() {
-            let mut env_info = EnvInfo::default();
-            env_info.gas_limit = U256::from(100_000_000);
             let running = Arc::new(AtomicBool::new(true));
-            let mut state = self.state.clone();
-            let machine = machine_generator();
             self.running = Some(Arc::downgrade(&running));
-            self.handler = Some(
-                thread::Builder::new()
-                    .name("secure_engine".to_string())
-                    .spawn(move || {
-                        for event in events {
-                            if running.load(Ordering::Relaxed) {
-                                match event {
-                                    ExecutionEvent::Transact(tx) => {
-                                        state.apply(&env_info, &machine, &tx, false).unwrap();
-                                    }
-                                    ExecutionEvent::ChangeEnv(env) => env_info = env,
-                                    ExecutionEvent::AddBalance(addr, amount) => {
-                                        state
-                                            .add_balance(&addr, &amount, CleanupMode::NoEmpty)
-                                            .unwrap();
-                                    }
-                                    _ => (),
-                                }
-                            }
-                        }
-                        state
-                    })
-                    .unwrap(),
-            );
+            let job = SecureJob {
+                state: self.state.clone(),
+                cache: self.cache.clone(),
+                events: events,
+                running: running,
+            };
+            // A worker which died, or was shut down, is replaced like a
+            // dead engine.
+            let job = match self.job_channel_tx {
+                Some(ref job_channel_tx) => match job_channel_tx.send(job) {
+                    Ok(()) => return Ok(()),
+                    Err(SendError(job)) => job,
+                },
+                None => job,
+            };
+            self.respawn();
+            if let Some(ref job_channel_tx) = self.job_channel_tx {
+                job_channel_tx.send(job).map_err(|_| died())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn respawn(&mut self) {
+        if let Some(handler) = self.handler.take() {
+            // the worker already exited
+            let _ = handler.join();
         }
+        let (job_channel_tx, result_channel_rx, handler) = spawn_secure(self.chain.clone());
+        self.job_channel_tx = Some(job_channel_tx);
+        self.result_channel_rx = result_channel_rx;
+        self.handler = Some(handler);
+    }
+
+    /// Whether a job was started and not joined yet.
+    pub fn is_running(&self) -> bool {
+        self.running.is_some()
     }
 
     pub fn get_events(&mut self, events: Vec<ExecutionEvent>) {
         self.execution_events = Some(events);
     }
 
-    pub fn join(&mut self) -> State<StateDB> {
-        self.handler.take().unwrap().join().unwrap()
+    /// Waits for the running job, returning its state and receipts, or the
+    /// first event it failed on. Fails at once if no job was started.
+    pub fn join(&mut self) -> Result<(State<StateDB>, Vec<Receipt>), Error> {
+        if self.running.take().is_none() {
+            return Err(Error::SecureIdle);
+        }
+        self.result_channel_rx.recv().map_err(|_| died())?
     }
 
-    pub fn terminate(&mut self) {
+    pub fn terminate(&mut self) -> Result<(), Error> {
         if let Some(running) = self.running.take() {
-            match running.upgrade() {
-                Some(running) => {
-                    (*running).store(false, Ordering::Relaxed);
-                    self.handler.take().unwrap().join().unwrap();
-                }
-                None => (),
+            if let Some(running) = running.upgrade() {
+                (*running).store(false, Ordering::Relaxed);
             }
+            // the result of a job has to be taken before the next one
+            self.result_channel_rx.recv().map_err(|_| died())?.ok();
         }
+        Ok(())
+    }
+
+    /// Terminates the running job, if any, and the worker thread.
+    pub fn shutdown(&mut self) -> Result<(), Error> {
+        let terminated = self.terminate();
+        self.job_channel_tx = None;
+        if let Some(handler) = self.handler.take() {
+            handler.join().map_err(|_| died())?;
+        }
+        terminated
     }
 }
 
-pub fn sequential_exec(state: &mut State<StateDB>, txs: &Vec<SignedTransaction>) {
-    let mut env_info = EnvInfo::default();
-    env_info.gas_limit = U256::from(100_000_000);
-    let machine = machine_generator();
+fn died() -> Error {
+    Error::EngineDied("secure_engine".to_string())
+}
 
-    for tx in txs {
-        state.apply(&env_info, &machine, &tx, false).unwrap();
+/// Starts the secure engine's worker thread, running the jobs it is sent
+/// one after another.
+fn spawn_secure(
+    chain: Chain,
+) -> (
+    Sender<SecureJob>,
+    Receiver<Result<(State<StateDB>, Vec<Receipt>), Error>>,
+    JoinHandle<()>,
+) {
+    let (job_channel_tx, job_channel_rx) = unbounded::<SecureJob>();
+    let (result_channel_tx, result_channel_rx) = unbounded();
+    let machine = chain.machine.clone();
+    let handler = thread::Builder::new()
+        .name("secure_engine".to_string())
+        .spawn(move || {
+            for job in job_channel_rx {
+                let SecureJob {
+                    mut state,
+                    cache,
+                    events,
+                    running,
+                } = job;
+                // nothing is changed before the first event
+                if let Some(cache) = cache {
+                    let mut addrs = HashSet::new();
+                    for event in &events {
+                        addrs.extend(transaction_accounts(event));
+                    }
+                    cache.warm(&mut state, &addrs.into_iter().collect::<Vec<_>>());
+                }
+                let mut env_info = chain.env_info();
+                let mut receipts = vec![];
+                let mut result = Ok(());
+                for event in events {
+                    if !running.load(Ordering::Relaxed) || result.is_err() {
+                        break;
+                    }
+                    match event {
+                        ExecutionEvent::Transact(tx) => {
+                            // one receipt per earlier transaction
+                            let index = receipts.len();
+                            result = state
+                                .apply(&env_info, &machine, &tx, false)
+                                .map(|outcome| {
+                                    let gas_used = outcome.receipt.gas_used;
+                                    receipts.push(own_gas_receipt(outcome.receipt, &env_info));
+                                    env_info.gas_used = gas_used;
+                                })
+                                .map_err(|e| Error::Execution(index, e));
+                        }
+                        ExecutionEvent::ChangeEnv(env) => env_info = env,
+                        ExecutionEvent::AddBalance(addr, amount) => {
+                            result = state
+                                .add_balance(&addr, &amount, CleanupMode::NoEmpty)
+                                .map_err(|e| Error::Reward(addr, e.into()));
+                        }
+                        _ => (),
+                    }
+                }
+                if result_channel_tx
+                    .send(result.map(|_| (state, receipts)))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        })
+        .unwrap();
+    (job_channel_tx, result_channel_rx, handler)
+}
+
+pub fn sequential_exec(
+    state: &mut State<StateDB>,
+    txs: &Vec<SignedTransaction>,
+    chain: &Chain,
+) -> Result<Vec<Receipt>, Error> {
+    let mut env_info = chain.env_info();
+    let machine = &chain.machine;
+
+    let mut receipts = vec![];
+    for (index, tx) in txs.iter().enumerate() {
+        let outcome = state
+            .apply(&env_info, &machine, &tx, false)
+            .map_err(|e| Error::Execution(index, e))?;
+        env_info.gas_used = outcome.receipt.gas_used;
+        receipts.push(outcome.receipt);
+    }
+    Ok(receipts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers;
+
+    #[test]
+    fn test_reset_drops_migrated_caches() {
+        let a = Address::from(1);
+        let mut state = test_helpers::get_temp_state();
+        state
+            .add_balance(&a, &U256::from(10), CleanupMode::NoEmpty)
+            .unwrap();
+        state.commit().unwrap();
+        let mut next_state = state.clone();
+        next_state
+            .add_balance(&a, &U256::from(10), CleanupMode::NoEmpty)
+            .unwrap();
+        next_state.commit().unwrap();
+
+        let engines: Vec<_> = (0..2)
+            .map(|number| {
+                ExecutionEngine::start(state.clone(), number, Chain::default(), DEFAULT_TIMEOUT)
+            })
+            .collect();
+        // the receiving engine is aborted before its wait, the cache still
+        // arrives
+        engines[1].abort();
+        engines[0]
+            .send_cache(vec![a], engines[1].cache_channel_tx())
+            .unwrap();
+        engines[1].wait_cache(vec![a]).unwrap();
+        for engine in &engines {
+            engine.stop(DEFAULT_TIMEOUT).unwrap();
+            engine.reset(next_state.clone()).unwrap();
+        }
+
+        // the next block's wait gets the next block's cache
+        engines[0]
+            .send_cache(vec![a], engines[1].cache_channel_tx())
+            .unwrap();
+        engines[1].wait_cache(vec![a]).unwrap();
+        engines[0].stop(DEFAULT_TIMEOUT).unwrap();
+        let (state, _) = engines[1].stop(DEFAULT_TIMEOUT).unwrap();
+        assert_eq!(state.balance(&a).unwrap(), U256::from(20));
+
+        for engine in engines {
+            engine.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_stop_timeout() {
+        let a = Address::from(1);
+        let mut state = test_helpers::get_temp_state();
+        state
+            .add_balance(&a, &U256::from(10), CleanupMode::NoEmpty)
+            .unwrap();
+        state.commit().unwrap();
+
+        let engines: Vec<_> = (0..2)
+            .map(|number| {
+                ExecutionEngine::start(state.clone(), number, Chain::default(), DEFAULT_TIMEOUT)
+            })
+            .collect();
+        // waits for a cache nobody sent yet
+        engines[1].wait_cache(vec![a]).unwrap();
+        engines[1].push_add_balance(a, U256::from(10)).unwrap();
+        match engines[1].stop(Duration::from_millis(50)) {
+            Err(Error::Timeout(_)) => (),
+            _ => panic!("the waiting engine makes no progress"),
+        }
+
+        // the timed out engine skips the rest of its block
+        engines[0]
+            .send_cache(vec![a], engines[1].cache_channel_tx())
+            .unwrap();
+        engines[0].stop(DEFAULT_TIMEOUT).unwrap();
+        let (state, executed) = engines[1].stop(DEFAULT_TIMEOUT).unwrap();
+        assert!(executed.is_empty());
+        assert_eq!(state.balance(&a).unwrap(), U256::from(10));
+
+        for engine in engines {
+            engine.shutdown().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_respawn_secure_engine() {
+        let transactions = test_helpers::static_dep_txs(20, 40, false);
+        let state = test_helpers::funded_state(&transactions, U256::from(1));
+        let expected = test_helpers::sequential_root(&state, &transactions, &Chain::default());
+
+        // the worker is gone, the next job starts a new one
+        let mut secure_engine = SecureEngine::new(state, Chain::default());
+        secure_engine.shutdown().unwrap();
+        secure_engine.get_events(
+            transactions
+                .into_iter()
+                .map(ExecutionEvent::Transact)
+                .collect(),
+        );
+        secure_engine.run().unwrap();
+        let (mut state, receipts) = secure_engine.join().unwrap();
+        state.commit().unwrap();
+        assert_eq!(state.root(), &expected);
+        assert_eq!(receipts.len(), 40);
+        secure_engine.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_join_idle_secure_engine() {
+        let mut secure_engine = SecureEngine::new(test_helpers::get_temp_state(), Chain::default());
+        match secure_engine.join() {
+            Err(Error::SecureIdle) => (),
+            _ => panic!("joined a secure engine without a job"),
+        }
+        secure_engine.shutdown().unwrap();
     }
 }
@@ -1,9 +1,20 @@
 #[macro_use]
 extern crate serde_derive;
+pub mod access_tracer;
+pub mod chain;
+pub mod commit;
+pub mod error;
 pub mod execution_engine;
+pub mod optimistic;
 pub mod parallel_manager;
+pub mod pipeline;
+pub mod prefetch;
+pub mod preparation;
 pub mod prune_state;
+pub mod receipts;
 pub mod reward;
+pub mod scheduler;
+pub mod shared_cache;
 pub mod test_helpers;
 
 #[cfg(test)]
@@ -2,10 +2,21 @@
 #[macro_use]
 extern crate serde_derive;
 extern crate env_logger;
+mod access_tracer;
+mod chain;
+mod commit;
+mod error;
 mod execution_engine;
+mod optimistic;
 mod parallel_manager;
+mod pipeline;
+mod preparation;
+mod prefetch;
 mod prune_state;
+mod receipts;
 mod reward;
+mod scheduler;
+mod shared_cache;
 mod test_helpers;
 
 fn main() {}
@@ -0,0 +1,441 @@
+use crate::access_tracer::{apply_recorded, AccessSet, StateKey};
+use crate::chain::Chain;
+use crate::error::Error;
+use crate::execution_engine::ExecutionEvent;
+use crate::receipts::{has_intermediate_root, own_gas_receipt};
+use common_types::receipt::Receipt;
+use ethcore::error::Error as EthcoreError;
+use ethcore::factory::Factories;
+use ethcore::open_state::{CleanupMode, State};
+use ethcore::open_state_db::StateDB;
+use ethereum_types::{Address, H256, U256};
+use hashbrown::HashMap;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Credits are versions too: a later read must have seen all of them.
+fn written_or_credited(access_set: &AccessSet) -> Vec<StateKey> {
+    let mut keys = access_set.written_keys();
+    keys.extend(
+        access_set
+            .credits
+            .keys()
+            .map(|addr| StateKey::Account(*addr)),
+    );
+    keys
+}
+
+/// Account values after a transaction.
+#[derive(Clone, Debug)]
+pub struct AccountWrite {
+    exists: bool,
+    balance: U256,
+    nonce: U256,
+    code: Option<Arc<Vec<u8>>>,
+}
+
+impl AccountWrite {
+    fn deleted() -> AccountWrite {
+        AccountWrite {
+            exists: false,
+            balance: U256::zero(),
+            nonce: U256::zero(),
+            code: None,
+        }
+    }
+}
+
+/// Values of everything a transaction wrote, captured right after it was
+/// applied, so it can be replayed on another state without re-execution.
+/// Credits are replayed as balance increments. An account only read which
+/// is gone afterwards, i.e. an empty account touched and cleared under
+/// EIP-161, is replayed as a deletion.
+#[derive(Clone, Debug, Default)]
+pub struct WriteSet {
+    accounts: Vec<(Address, AccountWrite)>,
+    storage: Vec<(Address, H256, H256)>,
+    credits: Vec<(Address, U256)>,
+}
+
+impl WriteSet {
+    pub fn capture(
+        state: &State<StateDB>,
+        access_set: &AccessSet,
+    ) -> Result<WriteSet, EthcoreError> {
+        let mut write_set = WriteSet::default();
+        for addr in &access_set.writes {
+            let account = AccountWrite {
+                exists: state.exists(addr)?,
+                balance: state.balance(addr)?,
+                nonce: state.nonce(addr)?,
+                code: state.code(addr)?,
+            };
+            write_set.accounts.push((*addr, account));
+        }
+        for addr in access_set.reads.difference(&access_set.writes) {
+            if !state.exists(addr)? {
+                write_set.accounts.push((*addr, AccountWrite::deleted()));
+            }
+        }
+        for (addr, key) in &access_set.storage_writes {
+            let value = state.storage_at(addr, key)?;
+            write_set.storage.push((*addr, *key, value));
+        }
+        for (addr, amount) in &access_set.credits {
+            write_set.credits.push((*addr, *amount));
+        }
+        Ok(write_set)
+    }
+
+    pub fn apply(&self, state: &mut State<StateDB>) -> Result<(), EthcoreError> {
+        for (addr, account) in &self.accounts {
+            if !account.exists {
+                state.kill_account(addr);
+                continue;
+            }
+            let balance = state.balance(addr)?;
+            if account.balance > balance || !state.exists(addr)? {
+                state.add_balance(addr, &(account.balance - balance), CleanupMode::ForceCreate)?;
+            } else if account.balance < balance {
+                state.sub_balance(
+                    addr,
+                    &(balance - account.balance),
+                    &mut CleanupMode::NoEmpty,
+                )?;
+            }
+            // State has no nonce setter, nonces only ever grow by a few
+            // within one transaction.
+            while state.nonce(addr)? < account.nonce {
+                state.inc_nonce(addr)?;
+            }
+            if let Some(ref code) = account.code {
+                if state.code(addr)?.as_ref() != Some(code) {
+                    state.init_code(addr, (**code).clone())?;
+                }
+            }
+        }
+        for (addr, key, value) in &self.storage {
+            state.set_storage(addr, *key, *value)?;
+        }
+        for (addr, amount) in &self.credits {
+            state.add_balance(addr, amount, CleanupMode::NoEmpty)?;
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of an optimistic execution of a single event.
+pub struct Execution {
+    pub engine: usize,
+    pub access_set: AccessSet,
+    pub write_set: WriteSet,
+    pub receipt: Receipt,
+}
+
+/// Writes of every optimistically executed transaction, versioned by the
+/// event index of the writer.
+#[derive(Default)]
+pub struct MultiVersionStore {
+    versions: HashMap<StateKey, BTreeMap<usize, usize>>,
+    executions: HashMap<usize, Execution>,
+}
+
+impl MultiVersionStore {
+    pub fn insert(&mut self, index: usize, execution: Execution) {
+        for key in written_or_credited(&execution.access_set) {
+            self.versions
+                .entry(key)
+                .or_insert_with(BTreeMap::new)
+                .insert(index, execution.engine);
+        }
+        self.executions.insert(index, execution);
+    }
+
+    pub fn execution(&self, index: usize) -> Option<&Execution> {
+        self.executions.get(&index)
+    }
+
+    /// The version of `key` the transaction at `index` saw when it was
+    /// executed on `engine`, `None` if it read the block's base state.
+    pub fn seen_version(&self, key: &StateKey, index: usize, engine: usize) -> Option<usize> {
+        self.versions.get(key).and_then(|versions| {
+            versions
+                .range(..index)
+                .rev()
+                .find(|(_, writer)| **writer == engine)
+                .map(|(index, _)| *index)
+        })
+    }
+}
+
+/// Validates optimistic executions against the state committed so far.
+#[derive(Default)]
+pub struct Validator {
+    // latest committed version of each key, and whether it was
+    // re-executed rather than taken from the optimistic run
+    committed: HashMap<StateKey, (usize, bool)>,
+}
+
+impl Validator {
+    /// An execution is valid if every key it touched was read at the same
+    /// version that has been committed, and that version came from an
+    /// optimistic run which was itself valid.
+    pub fn validate(&self, store: &MultiVersionStore, index: usize) -> bool {
+        let execution = match store.execution(index) {
+            Some(execution) => execution,
+            None => return false,
+        };
+        execution.access_set.touched_keys().iter().all(|key| {
+            let seen = store.seen_version(key, index, execution.engine);
+            match self.committed.get(key) {
+                Some((version, reexecuted)) => seen == Some(*version) && !reexecuted,
+                None => seen.is_none(),
+            }
+        })
+    }
+
+    pub fn commit(&mut self, index: usize, access_set: &AccessSet, reexecuted: bool) {
+        for key in written_or_credited(access_set) {
+            self.committed.insert(key, (index, reexecuted));
+        }
+    }
+}
+
+/// Starts an optimistic worker executing the given events, in order, on
+/// its own copy of the state.
+pub fn spawn_worker(
+    mut state: State<StateDB>,
+    number: usize,
+    events: Vec<(usize, ExecutionEvent)>,
+    chain: Chain,
+) -> JoinHandle<Vec<(usize, Execution)>> {
+    thread::Builder::new()
+        .name(format!(
+            "{}{}",
+            "optimistic".to_string(),
+            &number.to_string()
+        ))
+        .spawn(move || {
+            let mut env_info = chain.env_info();
+            let machine = chain.machine;
+            let mut executions = vec![];
+            for (index, event) in events {
+                match event {
+                    ExecutionEvent::Transact(tx) => {
+                        let applied = apply_recorded(&mut state, &env_info, &machine, &tx);
+                        // An invalid transaction, e.g. a nonce which is not
+                        // yet reached, is left for re-execution, as is one
+                        // whose writes can't be read back.
+                        if let Ok((outcome, access_set)) = applied {
+                            let gas_used = outcome.receipt.gas_used;
+                            let receipt = own_gas_receipt(outcome.receipt, &env_info);
+                            env_info.gas_used = gas_used;
+                            let write_set = match WriteSet::capture(&state, &access_set) {
+                                Ok(write_set) => write_set,
+                                Err(_) => continue,
+                            };
+                            executions.push((
+                                index,
+                                Execution {
+                                    engine: number,
+                                    access_set: access_set,
+                                    write_set: write_set,
+                                    receipt: receipt,
+                                },
+                            ));
+                        }
+                    }
+                    ExecutionEvent::ChangeEnv(env) => env_info = env,
+                    _ => (),
+                }
+            }
+            executions
+        })
+        .unwrap()
+}
+
+/// Executes the events optimistically on `threads` workers, then validates
+/// every transaction in block order against a multi-version store of the
+/// optimistic writes. Valid transactions are committed from their captured
+/// writes, the others are re-executed on the committed state. The block is
+/// committed into `state_db` on top of `root`, returning the receipts of
+/// `own_gas_receipt` and the number of re-executed transactions.
+///
+/// A receipt carrying an intermediate state root, as before EIP-658, is
+/// never valid, such blocks are executed in block order.
+pub fn apply_block(
+    state_db: &mut StateDB,
+    root: &mut H256,
+    factories: &Factories,
+    chain: &Chain,
+    events: &[ExecutionEvent],
+    threads: usize,
+) -> Result<(Vec<Receipt>, usize), Error> {
+    let threads = threads.max(1);
+    let mut assigned = vec![vec![]; threads];
+    let mut senders = HashMap::new();
+    let mut next_thread = 0;
+    for (index, event) in events.iter().enumerate() {
+        match event {
+            ExecutionEvent::Transact(tx) => {
+                // transactions of one sender stay in order on one worker
+                let tid = *senders.entry(tx.sender()).or_insert_with(|| {
+                    let tid = next_thread;
+                    next_thread = (next_thread + 1) % threads;
+                    tid
+                });
+                assigned[tid].push((index, event.clone()));
+            }
+            ExecutionEvent::ChangeEnv(_) => {
+                for worker_events in &mut assigned {
+                    worker_events.push((index, event.clone()));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut workers = vec![];
+    for (number, worker_events) in assigned.into_iter().enumerate() {
+        workers.push(spawn_worker(
+            state_at(state_db, root, factories),
+            number,
+            worker_events,
+            chain.clone(),
+        ));
+    }
+    let mut store = MultiVersionStore::default();
+    for (number, worker) in workers.into_iter().enumerate() {
+        let executions = worker
+            .join()
+            .map_err(|_| Error::EngineDied(format!("optimistic{}", number)))?;
+        for (index, execution) in executions {
+            store.insert(index, execution);
+        }
+    }
+
+    let mut state = state_at(state_db, root, factories);
+    let mut validator = Validator::default();
+    let mut env_info = chain.env_info();
+    let machine = chain.machine.clone();
+    let mut reexecuted = 0;
+    let mut receipts = vec![];
+    for (index, event) in events.iter().enumerate() {
+        match event {
+            ExecutionEvent::Transact(tx) => {
+                // workers only checked the gas limit against their own
+                // transactions
+                let within_gas_limit = env_info.gas_used + tx.gas <= env_info.gas_limit;
+                let valid = within_gas_limit
+                    && validator.validate(&store, index)
+                    && !has_intermediate_root(&store.execution(index).unwrap().receipt);
+                if valid {
+                    let execution = store.execution(index).unwrap();
+                    execution
+                        .write_set
+                        .apply(&mut state)
+                        .map_err(Error::Commit)?;
+                    validator.commit(index, &execution.access_set, false);
+                    env_info.gas_used = env_info.gas_used + execution.receipt.gas_used;
+                    receipts.push(execution.receipt.clone());
+                } else {
+                    let (outcome, access_set) = apply_recorded(&mut state, &env_info, &machine, tx)
+                        .map_err(|e| Error::Execution(receipts.len(), e))?;
+                    validator.commit(index, &access_set, true);
+                    let gas_used = outcome.receipt.gas_used;
+                    receipts.push(own_gas_receipt(outcome.receipt, &env_info));
+                    env_info.gas_used = gas_used;
+                    reexecuted += 1;
+                }
+            }
+            ExecutionEvent::AddBalance(addr, amount) => {
+                state
+                    .add_balance(addr, amount, CleanupMode::NoEmpty)
+                    .map_err(|e| Error::Reward(*addr, e.into()))?;
+                validator.commit(index, &AccessSet::add_balance(*addr, *amount), true);
+            }
+            ExecutionEvent::ChangeEnv(env) => env_info = env.clone(),
+            _ => (),
+        }
+    }
+    state
+        .commit_external(state_db, root, true)
+        .map_err(|e| Error::Commit(e.into()))?;
+    Ok((receipts, reexecuted))
+}
+
+fn state_at(state_db: &StateDB, root: &H256, factories: &Factories) -> State<StateDB> {
+    State::from_existing(
+        state_db.boxed_clone_canon(root),
+        root.clone(),
+        U256::from(0),
+        factories.clone(),
+    )
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::execution_engine::sequential_exec;
+    use crate::parallel_manager::ParallelManager;
+    use crate::test_helpers;
+    use common_types::transaction::{Action, Transaction};
+    use ethstore::ethkey::{Generator, Random};
+
+    #[test]
+    fn test_optimistic_100_4() {
+        let transactions = test_helpers::static_dep_txs(50, 100, false);
+        let state = test_helpers::funded_state(&transactions, U256::from(1));
+        let mut expected = state.clone();
+
+        let mut parallel_manager = ParallelManager::new(state);
+        parallel_manager.add_transactions(transactions.clone());
+        parallel_manager.apply_optimistic(4).unwrap();
+
+        let receipts = sequential_exec(&mut expected, &transactions, &Chain::default()).unwrap();
+        expected.commit().unwrap();
+
+        assert_eq!(expected.root(), parallel_manager.state_root());
+        assert_eq!(&receipts, parallel_manager.receipts());
+        assert_eq!(
+            receipts.last().unwrap().gas_used,
+            parallel_manager.gas_used()
+        );
+    }
+
+    #[test]
+    fn test_touched_deletion() {
+        // a zero value call clears the empty account it touches
+        let empty = Address::random();
+        let tx = Transaction {
+            action: Action::Call(empty),
+            value: U256::zero(),
+            data: vec![],
+            gas: U256::from(21_000),
+            gas_price: U256::zero(),
+            nonce: U256::zero(),
+        }
+        .sign(Random.generate().unwrap().secret(), None);
+        let mut state = test_helpers::funded_state(&[tx.clone()], U256::from(1));
+        state
+            .add_balance(&empty, &U256::zero(), CleanupMode::ForceCreate)
+            .unwrap();
+        state.commit().unwrap();
+        let mut replayed = state.clone();
+
+        let chain = Chain::default();
+        let (_, access_set) =
+            apply_recorded(&mut state, &chain.env_info(), &chain.machine, &tx).unwrap();
+        assert!(!state.exists(&empty).unwrap());
+        let write_set = WriteSet::capture(&state, &access_set).unwrap();
+        assert!(replayed.exists(&empty).unwrap());
+        write_set.apply(&mut replayed).unwrap();
+        assert!(!replayed.exists(&empty).unwrap());
+
+        state.commit().unwrap();
+        replayed.commit().unwrap();
+        assert_eq!(state.root(), replayed.root());
+    }
+}
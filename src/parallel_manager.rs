@@ -1,15 +1,126 @@
-use crate::execution_engine::{ExecutionEngine, ExecutionEvent, SecureEngine};
+use crate::access_tracer::{AccessSet, StateKey};
+use crate::chain::Chain;
+use crate::commit::commit_parallel;
+use crate::error::Error;
+use crate::execution_engine::{ExecutionEngine, ExecutionEvent, SecureEngine, DEFAULT_TIMEOUT};
+use crate::optimistic;
+use crate::prefetch::{self, Prefetcher};
+use crate::preparation::PreparedBlock;
+use crate::receipts::{self, has_intermediate_root, own_gas_receipt};
 use crate::reward::Reward;
+use crate::scheduler::{Assignment, DependencyScheduler, Scheduler};
+use crate::shared_cache::{BlockCache, SharedCache};
+use common_types::receipt::Receipt;
 use common_types::transaction::{Action, SignedTransaction};
 use ethcore::factory::Factories;
-use ethcore::open_state::State;
+use ethcore::open_state::{CleanupMode, State};
 use ethcore::open_state_db::StateDB;
-use ethereum_types::{Address, H256, U256};
-use hashbrown::HashMap;
+use ethereum_types::{Address, Bloom, H256, U256};
+use hashbrown::{HashMap, HashSet};
+use log::warn;
 use std::clone::Clone;
-use std::ops::Deref;
+use std::time::Duration;
 use vm::EnvInfo;
 
+/// Why the result of the engines was dropped in favour of the secure
+/// engine.
+#[derive(Debug)]
+pub enum Fallback {
+    /// An engine died, or could not be reached.
+    EngineFailure(Error),
+    /// The event failed on its engine.
+    EventFailed {
+        /// Index of the event in the block's events.
+        event: usize,
+        /// Position of the transaction, as in `Error::Execution`, `None` if
+        /// the event is not a transaction.
+        transaction: Option<usize>,
+    },
+    /// The engines' transactions exceed the block gas limit at their block
+    /// positions.
+    GasLimitExceeded,
+    /// The data races could not be resolved by selective re-execution.
+    DataRace,
+    /// Selective re-execution failed at the event, by event index and
+    /// transaction position as in `EventFailed`.
+    Reexecution {
+        event: usize,
+        transaction: Option<usize>,
+    },
+    /// The engines were aborted while running, at the conflict of the
+    /// event, by event index and transaction position as in `EventFailed`.
+    /// See `ParallelManager::set_abort_threshold`.
+    Aborted {
+        event: usize,
+        transaction: Option<usize>,
+    },
+    /// The receipts carry the state root after each transaction, as before
+    /// EIP-658, which only block order execution can compute.
+    IntermediateRoots,
+}
+
+/// When the secure engine runs the sequential shadow execution of a block.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SecurePolicy {
+    /// Alongside the engines, so a fallback only waits for it to finish.
+    Eager,
+    /// Only once the engines' result of the block can't be used.
+    Lazy,
+    /// Never, a block whose engines' result can't be used fails with
+    /// `Error::SecureDisabled`.
+    Disabled,
+    /// Eager if the predicted conflict risk of the block exceeds the given
+    /// rate, lazy otherwise. The risk is the higher of the share of events
+    /// depending on an earlier event of the block, and the share of recent
+    /// blocks which raced.
+    Adaptive(f64),
+}
+
+/// Weight of the last block in the race rate of recent blocks.
+const RACE_RATE_WEIGHT: f64 = 0.1;
+
+/// An event touching an account or storage slot whose latest write, or a
+/// credit, lives in another engine's state.
+///
+/// Events are given by their index in the block's events, and, for
+/// transactions, by their position among the transactions as in
+/// `Error::Execution` and the receipts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Conflict {
+    pub key: StateKey,
+    /// Engine the account was last assigned to by the scheduler.
+    pub owner: Option<usize>,
+    /// Engine, event index and transaction position of the write or credit.
+    pub writer: usize,
+    pub write_index: usize,
+    pub write_transaction: Option<usize>,
+    /// Engine, event index and transaction position of the access.
+    pub engine: usize,
+    pub index: usize,
+    pub transaction: Option<usize>,
+}
+
+/// Data races found by `ParallelManager::stop`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConflictReport {
+    /// Every conflicting access, in block order.
+    pub conflicts: Vec<Conflict>,
+    /// Events picked for selective re-execution, by event index.
+    pub reexecution: Vec<usize>,
+    /// Whether the engines' result can't be applied as is, in which case
+    /// the block is applied by `apply_selective`.
+    pub race: bool,
+}
+
+impl ConflictReport {
+    fn race() -> ConflictReport {
+        ConflictReport {
+            race: true,
+            ..Default::default()
+        }
+    }
+}
+
 pub struct ParallelManager {
     // transactions
     events: Vec<ExecutionEvent>,
@@ -18,13 +129,59 @@ pub struct ParallelManager {
     state_db: StateDB,
     state_root: H256,
     factories: Factories,
+    // trie nodes read by the engines and the secure engine in this block
+    cache: SharedCache,
+    // threads warming the cache, none if 0
+    prefetch_threads: usize,
+    // storage slots to warm with the accounts of the next block
+    storage_hints: HashMap<Address, Vec<H256>>,
+    prefetcher: Option<Prefetcher>,
+    // whether the scheduler keeps the accounts of the engines across blocks
+    carry_over: bool,
+    // keys written by the previous block, which was not committed yet when
+    // this block started, see `follow`
+    pending_writes: Option<HashSet<StateKey>>,
+    // keys written by this block, if its engines' result was used as is
+    block_writes: Option<HashSet<StateKey>>,
+
+    // machine and default env of all engines
+    chain: Chain,
 
     // for parallel execution
-    dependency_table: HashMap<Address, usize>,
+    scheduler: Box<dyn Scheduler>,
     engines: Vec<ExecutionEngine>,
-    best_thread: usize,
+    // stopped engines, reused by the next block
+    pool: Vec<ExecutionEngine>,
+    // watchdog timeout of the engines
+    timeout: Duration,
+    // conflicts tolerated while the engines run, unchecked if `None`
+    abort_threshold: Option<usize>,
+    secure_policy: SecurePolicy,
+    // moving average of the blocks which raced
+    race_rate: f64,
     threads: usize,
     engine_states: Vec<State<StateDB>>,
+    // accounts each engine state may have changed
+    engine_accounts: Vec<HashSet<Address>>,
+
+    // for data race detection
+    // indices of the events dispatched to each engine, in order
+    dispatched: Vec<Vec<usize>>,
+    // (event index, address, from engine, to engine) of cache migrations
+    migrations: Vec<(usize, Address, usize, usize)>,
+    // storage slots written on shared contracts, merged after commit
+    storage_merges: Vec<(Address, H256, H256)>,
+    // (credit, debit) of commutative balance changes, merged after commit
+    balance_merges: HashMap<Address, (U256, U256)>,
+    // indices of the events to re-execute after a data race
+    reexecution: Option<Vec<usize>>,
+    // why the engines' result of the block was dropped, if it was
+    fallback: Option<Fallback>,
+
+    // receipts of the engines by event index
+    engine_receipts: Vec<(usize, Receipt)>,
+    // receipts of the applied block, in block order
+    receipts: Vec<Receipt>,
 
     // secure thread
     secure_engine: SecureEngine,
@@ -33,16 +190,40 @@ pub struct ParallelManager {
 impl Clone for ParallelManager {
     fn clone(&self) -> Self {
         let state = self.state();
-        let secure_engine = SecureEngine::new(state);
+        let mut secure_engine = SecureEngine::new(state, self.chain.clone());
+        // a cache of its own, refreshing it must not drop this one's
+        let cache = SharedCache::default();
+        secure_engine.set_cache(cache.at(&self.state_db, &self.state_root, &self.factories));
         ParallelManager {
             events: self.events.clone(),
             state_db: self.state_db.boxed_clone(),
             state_root: self.state_root.clone(),
             factories: self.factories.clone(),
-            dependency_table: HashMap::new(),
+            cache: cache,
+            prefetch_threads: self.prefetch_threads,
+            storage_hints: self.storage_hints.clone(),
+            prefetcher: None,
+            carry_over: self.carry_over,
+            pending_writes: None,
+            block_writes: None,
+            chain: self.chain.clone(),
+            scheduler: self.scheduler.boxed_clone(),
             engines: vec![],
+            pool: vec![],
+            timeout: self.timeout,
+            abort_threshold: self.abort_threshold,
+            secure_policy: self.secure_policy,
+            race_rate: self.race_rate,
             engine_states: vec![],
-            best_thread: 0,
+            engine_accounts: vec![],
+            dispatched: vec![],
+            migrations: vec![],
+            storage_merges: vec![],
+            balance_merges: HashMap::new(),
+            reexecution: None,
+            fallback: None,
+            engine_receipts: vec![],
+            receipts: vec![],
             threads: 0,
             secure_engine: secure_engine,
         }
@@ -51,25 +232,62 @@ impl Clone for ParallelManager {
 
 impl ParallelManager {
     pub fn new(state: State<StateDB>) -> ParallelManager {
-        let (root, state_db) = state.clone().drop();
-        ParallelManager {
+        ParallelManager::with_chain(state, Chain::default())
+    }
+
+    pub fn with_chain(state: State<StateDB>, chain: Chain) -> ParallelManager {
+        let (root, state_db) = state.drop();
+        let secure_state = State::from_existing(
+            state_db.boxed_clone_canon(&root),
+            root.clone(),
+            U256::from(0),
+            Factories::default(),
+        )
+        .unwrap();
+        let mut parallel_manager = ParallelManager {
             events: vec![],
             state_db: state_db,
             state_root: root,
             factories: Factories::default(),
-            dependency_table: HashMap::new(),
+            cache: SharedCache::default(),
+            prefetch_threads: 0,
+            storage_hints: HashMap::new(),
+            prefetcher: None,
+            carry_over: false,
+            pending_writes: None,
+            block_writes: None,
+            chain: chain.clone(),
+            scheduler: Box::new(DependencyScheduler::default()),
             engines: vec![],
+            pool: vec![],
+            timeout: DEFAULT_TIMEOUT,
+            abort_threshold: None,
+            secure_policy: SecurePolicy::Eager,
+            race_rate: 0.0,
             engine_states: vec![],
-            best_thread: 0,
+            engine_accounts: vec![],
+            dispatched: vec![],
+            migrations: vec![],
+            storage_merges: vec![],
+            balance_merges: HashMap::new(),
+            reexecution: None,
+            fallback: None,
+            engine_receipts: vec![],
+            receipts: vec![],
             threads: 0,
-            secure_engine: SecureEngine::new(state),
-        }
+            secure_engine: SecureEngine::new(secure_state, chain),
+        };
+        let cache = parallel_manager.block_cache();
+        parallel_manager.secure_engine.set_cache(cache);
+        parallel_manager
     }
 
+    /// Replaces the state the next block runs on.
     pub fn set_state(&mut self, state: State<StateDB>) {
         let (root, state_db) = state.drop();
         self.state_root = root;
         self.state_db = state_db;
+        self.reset_secure();
     }
 
     pub fn add_transactions(&mut self, mut txs: Vec<SignedTransaction>) {
@@ -78,6 +296,17 @@ impl ParallelManager {
         }
     }
 
+    /// Adds the transactions of a prepared block, refusing a block with a
+    /// transaction whose sender could not be recovered. The env info and
+    /// the rewards of the block are added on their own.
+    pub fn add_prepared_block(&mut self, block: &PreparedBlock) -> Result<(), Error> {
+        if let Some((index, e)) = block.invalid.first() {
+            return Err(Error::InvalidSignature(*index, e.clone()));
+        }
+        self.add_transactions(block.transactions.clone());
+        Ok(())
+    }
+
     pub fn add_reward(&mut self, reward: &Reward) {
         self.events.push(ExecutionEvent::AddBalance(
             reward.miner.clone().into(),
@@ -95,19 +324,122 @@ impl ParallelManager {
         self.events.push(ExecutionEvent::ChangeEnv(env_info));
     }
 
+    /// Replaces the events of the block.
+    pub fn set_events(&mut self, events: Vec<ExecutionEvent>) {
+        self.events = events;
+    }
+
     pub fn clone_to_secure(&mut self) {
         self.secure_engine.get_events(self.events.clone());
     }
 
+    /// Adds engines for the next block, reusing the stopped ones of the
+    /// pool before starting new threads.
     pub fn add_engines(&mut self, number: usize) {
-        for i in 0..number {
-            self.engines.push(ExecutionEngine::start(self.state(), i));
+        let cache = self.block_cache();
+        for _ in 0..number {
+            let engine = if self.pool.is_empty() {
+                let number = self.engines.len();
+                ExecutionEngine::start(self.state(), number, self.chain.clone(), self.timeout)
+            } else {
+                let engine = self.pool.remove(0);
+                match engine.reset(self.state()) {
+                    Ok(()) => engine,
+                    // replace a dead engine
+                    Err(_) => {
+                        let number = self.engines.len();
+                        ExecutionEngine::start(
+                            self.state(),
+                            number,
+                            self.chain.clone(),
+                            self.timeout,
+                        )
+                    }
+                }
+            };
+            // a dead engine fails on dispatch
+            let _ = engine.warm(cache.clone());
+            self.engines.push(engine);
         }
     }
 
+    /// Replaces the policy dispatching events to the engines, from the next
+    /// block on.
+    pub fn set_scheduler(&mut self, scheduler: Box<dyn Scheduler>) {
+        self.scheduler = scheduler;
+    }
+
+    /// Keeps the accounts assigned to the engines by a block for the next
+    /// blocks, instead of starting each block with an empty assignment.
+    pub fn set_carry_over(&mut self, carry_over: bool) {
+        self.carry_over = carry_over;
+    }
+
+    /// Sets how long engines wait for each other, and the manager for an
+    /// engine to execute its next event, before the block falls back to
+    /// the secure engine. Pooled
+    /// engines are shut down, so that the next block starts engines with
+    /// the new timeout.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+        for engine in self.pool.drain(..) {
+            let _ = engine.shutdown();
+        }
+    }
+
+    /// Checks the accesses the engines stream back while they run, in block
+    /// order, and aborts them as soon as an event failed or more than
+    /// `threshold` conflicts were found, instead of waiting for the whole
+    /// block. `None` leaves all checks to the end of the block.
+    pub fn set_abort_threshold(&mut self, threshold: Option<usize>) {
+        self.abort_threshold = threshold;
+    }
+
+    /// Loads the senders and receivers of the block, with their code and
+    /// the hinted storage slots, on `threads` threads while the engines run.
+    /// 0 disables prefetching.
+    pub fn set_prefetch_threads(&mut self, threads: usize) {
+        self.prefetch_threads = threads;
+    }
+
+    /// Storage slots of `addr` the next block is expected to read, loaded
+    /// with the account when prefetching.
+    pub fn hint_storage(&mut self, addr: Address, keys: Vec<H256>) {
+        self.storage_hints.entry(addr).or_default().extend(keys);
+    }
+
+    pub fn set_secure_policy(&mut self, policy: SecurePolicy) {
+        self.secure_policy = policy;
+    }
+
+    /// Share of recent blocks whose engines' result could not be applied as
+    /// is.
+    pub fn race_rate(&self) -> f64 {
+        self.race_rate
+    }
+
+    /// Terminates the pooled engines and the secure engine, returning the
+    /// first error if some of them already died.
+    pub fn shutdown(&mut self) -> Result<(), Error> {
+        if let Some(prefetcher) = self.prefetcher.take() {
+            prefetcher.stop();
+        }
+        let mut result = Ok(());
+        for engine in self.engines.drain(..) {
+            result = result.and(engine.stop(self.timeout).map(|_| ()));
+            result = result.and(engine.shutdown());
+        }
+        for engine in self.pool.drain(..) {
+            result = result.and(engine.shutdown());
+        }
+        result.and(self.secure_engine.shutdown())
+    }
+
+    /// State at the current root, sharing the canonical account cache of
+    /// the database.
     pub fn state(&self) -> State<StateDB> {
         State::from_existing(
-            self.state_db.boxed_clone_canon(&self.state_root()),
+            self.state_db.boxed_clone_canon(&self.state_root),
             self.state_root().clone(),
             U256::from(0),
             self.factories.clone(),
@@ -115,140 +447,738 @@ impl ParallelManager {
         .unwrap()
     }
 
-    pub fn consume(&mut self) {
-        self.secure_engine.run();
+    /// Reads of the accounts at the current root through the manager's
+    /// cache, dropping the accounts cached for earlier roots.
+    pub fn block_cache(&self) -> BlockCache {
+        self.cache.refresh(&self.state_root);
+        self.cache
+            .at(&self.state_db, &self.state_root, &self.factories)
+    }
+
+    fn reset_secure(&mut self) {
+        self.secure_engine.set_state(self.state());
+        let cache = self.block_cache();
+        self.secure_engine.set_cache(cache);
+    }
+
+    pub fn consume(&mut self) -> Result<(), Error> {
+        if let Some(prefetcher) = self.prefetcher.take() {
+            prefetcher.stop();
+        }
+        let hints: HashMap<_, _> = self.storage_hints.drain().collect();
+        if self.prefetch_threads > 0 {
+            let targets = prefetch::targets(&self.events, &hints);
+            self.prefetcher = Some(Prefetcher::start(
+                self.block_cache(),
+                self.prefetch_threads,
+                targets,
+            ));
+        }
+        let eager = match self.secure_policy {
+            SecurePolicy::Eager => true,
+            SecurePolicy::Lazy | SecurePolicy::Disabled => false,
+            SecurePolicy::Adaptive(threshold) => {
+                dependency_rate(&self.events).max(self.race_rate) > threshold
+            }
+        };
+        // the secure engine needs the previous block committed
+        if eager && self.pending_writes.is_none() {
+            self.secure_engine.run()?;
+        }
+        self.fallback = None;
         if self.engines.is_empty() {
-            return;
+            return Ok(());
         }
-        for event in self.events.clone() {
+        self.dispatched = vec![vec![]; self.engines.len()];
+        self.migrations = vec![];
+        self.storage_merges = vec![];
+        self.balance_merges = HashMap::new();
+        self.reexecution = None;
+        self.engine_receipts = vec![];
+        if self.carry_over {
+            self.scheduler.retain(self.engines.len());
+        } else {
+            self.scheduler.reset(self.engines.len());
+        }
+        // The secure engine runs the same events, a dead engine only costs
+        // the parallel result.
+        if let Err(e) = self.dispatch() {
+            self.fallback = Some(Fallback::EngineFailure(e));
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self) -> Result<(), Error> {
+        let state = self.state();
+        self.scheduler.prepare(&self.events, &state);
+        for (index, event) in self.events.clone().into_iter().enumerate() {
+            let assignment = match self.scheduler.schedule(index, &event, &state) {
+                Some(assignment) => assignment,
+                None => {
+                    if let ExecutionEvent::ChangeEnv(env_info) = event {
+                        for engine in &self.engines {
+                            engine.push_env(env_info.clone())?;
+                        }
+                    }
+                    continue;
+                }
+            };
+            let exec_tid = self.migrate(index, assignment)?;
             match event {
                 ExecutionEvent::Transact(tx) => {
-                    let to = match tx.deref().deref().action {
-                        Action::Create => Address::zero(),
-                        Action::Call(addr) => addr,
-                    };
-                    let exec_tid = self.get_exec_tid(&tx.sender(), &to);
-                    self.engines[exec_tid].push_transaction(tx.clone());
+                    self.engines[exec_tid].push_transaction(tx)?;
                 }
                 ExecutionEvent::AddBalance(addr, amount) => {
-                    let exec_tid = self.get_exec_tid(&addr, &Address::zero());
-                    self.engines[exec_tid].push_add_balance(addr, amount);
+                    self.engines[exec_tid].push_add_balance(addr, amount)?;
                 }
-                ExecutionEvent::ChangeEnv(env_info) => {
-                    for engine in &self.engines {
-                        engine.push_env(env_info.clone());
-                    }
-                }
-                _ => (),
+                _ => continue,
             }
+            self.dispatched[exec_tid].push(index);
+        }
+        Ok(())
+    }
+
+    /// Moves the account caches of an assignment to its engine in one
+    /// round: each owning engine drops its accounts and sends their caches,
+    /// which the target engine waits for before executing anything else.
+    fn migrate(&mut self, index: usize, assignment: Assignment) -> Result<usize, Error> {
+        let exec_tid = assignment.engine;
+        let mut sends: Vec<(usize, Vec<Address>)> = vec![];
+        let mut waits = vec![];
+        for (addr, drop_tid) in assignment.migrations {
+            if drop_tid == exec_tid || waits.contains(&addr) {
+                continue;
+            }
+            match sends.iter_mut().find(|(tid, _)| *tid == drop_tid) {
+                Some((_, addrs)) => addrs.push(addr),
+                None => sends.push((drop_tid, vec![addr])),
+            }
+            waits.push(addr);
+            self.migrations.push((index, addr, drop_tid, exec_tid));
+        }
+        if waits.is_empty() {
+            return Ok(exec_tid);
+        }
+        for (drop_tid, addrs) in sends {
+            let cache_channel_tx = self.engines[exec_tid].cache_channel_tx();
+            self.engines[drop_tid].send_cache(addrs, cache_channel_tx)?;
         }
+        self.engines[exec_tid].wait_cache(waits)?;
+        Ok(exec_tid)
+    }
+
+    pub fn state_root(&self) -> &H256 {
+        &self.state_root
+    }
+
+    pub fn set_root(&mut self, root: H256) {
+        self.state_root = root;
+    }
+
+    /// Starts the next block on the state `previous` ran its block on,
+    /// before `previous` is applied. Returns false if the keys written by
+    /// `previous` are not known, i.e. it raced or fell back, in which case
+    /// the next block has to wait for its commit.
+    ///
+    /// Events touching a key written by `previous` are re-executed once the
+    /// block is moved on top of it with `rebase`, which has to happen before
+    /// `stop`. Until then the secure engine doesn't run, and an event failing
+    /// on an engine is re-executed with all events after it rather than
+    /// failing the block, as it may only be invalid on the old state.
+    pub fn follow(&mut self, previous: &ParallelManager) -> bool {
+        let writes = match &previous.block_writes {
+            Some(writes) => writes.clone(),
+            None => return false,
+        };
+        self.state_db = previous.state_db.boxed_clone();
+        self.state_root = previous.state_root.clone();
+        self.scheduler = previous.scheduler.boxed_clone();
+        self.pending_writes = Some(writes);
+        true
+    }
+
+    /// Moves a block started with `follow` on top of `previous` once it is
+    /// applied.
+    pub fn rebase(&mut self, previous: &ParallelManager) {
+        self.state_db = previous.state_db.boxed_clone();
+        self.state_root = previous.state_root.clone();
+        self.reset_secure();
     }
 
-    fn get_exec_tid(&mut self, sender: &Address, to: &Address) -> usize {
-        let mut dependency_level = 0;
-        // dependency thread id.
-        let mut dependency_tid = [0, 0];
-        // address need to be insert to dependency table, possibly
-        // ethereum address of transaction sender and receiver.
-        let mut insert_addr = [sender.clone(), to.clone()];
+    /// Stops the engines and checks their accesses for data races. On a
+    /// race the engine states are kept without the accounts written by the
+    /// racing events, see `apply_selective`. If the engines' result can't
+    /// be used at all the block falls back to the secure engine, see
+    /// `fallback`. An event failing on an engine is left to the secure
+    /// engine, which reports it if it is invalid in block order too.
+    ///
+    /// The report lists every conflicting access found in the engines'
+    /// result, with the engine the scheduler assigned the account to.
+    pub fn stop(&mut self) -> Result<ConflictReport, Error> {
+        let report = self.detect_races()?;
+        let race = if report.race { 1.0 } else { 0.0 };
+        self.race_rate = self.race_rate * (1.0 - RACE_RATE_WEIGHT) + race * RACE_RATE_WEIGHT;
+        Ok(report)
+    }
 
-        // Find static dependency between threads, and count the
-        // dependency level.
-        for i in 0..2 {
-            match self.dependency_table.get(&insert_addr[i]) {
-                Some(tid) => {
-                    dependency_tid[i] = *tid;
-                    dependency_level = dependency_level + i + 1;
-                    insert_addr[i] = Address::zero();
+    fn detect_races(&mut self) -> Result<ConflictReport, Error> {
+        let online = self.monitor();
+        self.block_writes = None;
+        let pending_writes = self.pending_writes.take();
+        if let Some(prefetcher) = self.prefetcher.take() {
+            prefetcher.stop();
+        }
+        let data_races = self.engines.is_empty();
+        let mut results = vec![];
+        while let Some(engine) = self.engines.pop() {
+            let result = engine.stop(self.timeout);
+            // A dead or stalled engine is not reused, a late migration can't
+            // reach the next block.
+            if result.is_ok() {
+                self.pool.insert(0, engine);
+            }
+            results.insert(0, result);
+        }
+        let mut accesses = vec![];
+        let mut failed: Option<usize> = None;
+        self.engine_accounts = vec![];
+        for (engine_number, result) in results.into_iter().enumerate() {
+            let (state, executed) = match result {
+                Ok(result) => result,
+                Err(e) => {
+                    self.fallback = Some(Fallback::EngineFailure(e));
+                    continue;
                 }
-                None => (),
+            };
+            let mut accounts: HashSet<Address> = self
+                .migrations
+                .iter()
+                .filter(|(_, _, _, to)| *to == engine_number)
+                .map(|(_, addr, _, _)| *addr)
+                .collect();
+            for (index, executed) in self.dispatched[engine_number].iter().zip(executed) {
+                match executed {
+                    Ok((access_set, receipt)) => {
+                        accounts.extend(access_set.touched().cloned());
+                        accounts.extend(access_set.credits.keys().cloned());
+                        accesses.push((*index, engine_number, access_set));
+                        if let Some(receipt) = receipt {
+                            self.engine_receipts.push((*index, receipt));
+                        }
+                    }
+                    Err(_) => failed = Some(failed.map_or(*index, |i| i.min(*index))),
+                }
+            }
+            self.engine_states.push(state);
+            self.engine_accounts.push(accounts);
+        }
+        if data_races {
+            return Ok(ConflictReport::race());
+        }
+        // events to re-execute whatever their accesses
+        let mut stale = vec![];
+        match (&self.fallback, failed, &pending_writes) {
+            (None, Some(index), None) => {
+                self.fallback = Some(Fallback::EventFailed {
+                    event: index,
+                    transaction: self.transaction_position(index),
+                })
             }
+            (None, Some(index), Some(_)) => stale.extend(index..self.events.len()),
+            _ => (),
         }
-        let mut exec_tid = self.best_thread;
-        if dependency_level == 1
-            || dependency_level == 2
-            || (dependency_level == 3 && dependency_tid[0] == dependency_tid[1])
+        if self.fallback.is_some() {
+            self.engine_states = vec![];
+            return Ok(ConflictReport {
+                conflicts: self.annotate(online),
+                reexecution: vec![],
+                race: true,
+            });
+        }
+        accesses.sort_by_key(|(index, _, _)| *index);
+        self.engine_receipts.sort_by_key(|(index, _)| *index);
+
+        // Engines only checked the gas limit against their own transactions.
+        if !self.within_gas_limit() {
+            self.fallback = Some(Fallback::GasLimitExceeded);
+            self.engine_states = vec![];
+            return Ok(ConflictReport::race());
+        }
+        if self
+            .engine_receipts
+            .iter()
+            .any(|(_, receipt)| has_intermediate_root(receipt))
         {
-            // If single dependency
-            if dependency_level == 3 {
-                dependency_level = 2;
-            }
-            exec_tid = dependency_tid[dependency_level - 1];
-        } else if dependency_level == 3 {
-            // 1. If double dependency, send DropAddress signal to sender_tid
-            // 2. Wait for the address cache from sender_tid, and transfer address cache to to_tid
-            let drop_tid;
-            drop_tid = dependency_tid[0];
-            exec_tid = dependency_tid[1];
+            self.fallback = Some(Fallback::IntermediateRoots);
+            self.engine_states = vec![];
+            return Ok(ConflictReport::race());
+        }
 
-            let cache_channel_tx = self.engines[exec_tid].cache_channel_tx();
-            self.engines[drop_tid].send_cache(sender.clone(), cache_channel_tx);
-            self.engines[exec_tid].wait_cache(sender.clone());
-            insert_addr[0] = sender.clone();
+        if let Some(pending_writes) = &pending_writes {
+            for (index, _, access_set) in &accesses {
+                // Credits are left out, they commute: an account only
+                // credited is merged as a delta on top of the previous
+                // block, see `merge_credits`.
+                let keys = access_set.touched_keys().into_iter();
+                // an account written by the previous block may have been
+                // killed along with its storage
+                let mut keys = keys.flat_map(|key| vec![StateKey::Account(key.address()), key]);
+                if keys.any(|key| pending_writes.contains(&key)) && !stale.contains(index) {
+                    stale.push(*index);
+                }
+            }
         }
 
-        // Update dependency table
-        for i in 0..2 {
-            if insert_addr[i] != Address::zero() {
-                self.dependency_table.insert(insert_addr[i], exec_tid);
+        let (writers, conflicts) = replay_accesses(&accesses, &self.migrations);
+        if conflicts.is_empty() && stale.is_empty() {
+            self.split_storage(&writers)?;
+            self.merge_credits(&accesses, &[], &HashSet::new());
+            let mut writes = HashSet::new();
+            for (_, _, access_set) in &accesses {
+                // A storage write changes the account's storage root too,
+                // so the next block must not commit the account on top of
+                // the old one.
+                for key in access_set.written_keys() {
+                    writes.insert(StateKey::Account(key.address()));
+                    writes.insert(key);
+                }
+                writes.extend(
+                    access_set
+                        .credits
+                        .keys()
+                        .map(|addr| StateKey::Account(*addr)),
+                );
             }
+            self.block_writes = Some(writes);
+            return Ok(ConflictReport::default());
         }
+        let mut report = ConflictReport {
+            conflicts: self.annotate(conflicts),
+            reexecution: vec![],
+            race: true,
+        };
 
-        if self.best_thread == exec_tid {
-            self.best_thread = (self.best_thread + 1) % self.engines.len();
+        match select_reexecution(&accesses, &self.migrations, &stale) {
+            Some((reexecution, drops)) => {
+                for (tid, addr) in &drops {
+                    self.engine_states[*tid].drop_account(addr);
+                }
+                let kept: Vec<_> = accesses
+                    .iter()
+                    .filter(|(index, _, _)| reexecution.binary_search(index).is_err())
+                    .cloned()
+                    .collect();
+                let (writers, conflicts) = replay_accesses(&kept, &self.migrations);
+                if conflicts.is_empty() {
+                    self.split_storage(&writers)?;
+                    self.merge_credits(&accesses, &reexecution, &drops);
+                    report.reexecution = reexecution.clone();
+                    self.reexecution = Some(reexecution);
+                } else {
+                    self.fallback = Some(Fallback::DataRace);
+                    self.engine_states = vec![];
+                }
+            }
+            None => {
+                self.fallback = Some(Fallback::DataRace);
+                self.engine_states = vec![];
+            }
         }
+        Ok(report)
+    }
 
-        exec_tid
+    /// Replays the accesses streamed by the running engines, see
+    /// `set_abort_threshold`. Returns the conflicts found before the
+    /// engines were aborted, or before all events were checked.
+    fn monitor(&mut self) -> Vec<Conflict> {
+        let threshold = match self.abort_threshold {
+            // events of a block following another may fail until rebased
+            Some(threshold)
+                if self.fallback.is_none()
+                    && !self.engines.is_empty()
+                    && self.pending_writes.is_none() =>
+            {
+                threshold
+            }
+            _ => return vec![],
+        };
+        let mut order: Vec<(usize, usize)> = self
+            .dispatched
+            .iter()
+            .enumerate()
+            .flat_map(|(tid, indices)| indices.iter().map(move |index| (*index, tid)))
+            .collect();
+        order.sort();
+
+        let mut replay = Replay::default();
+        let mut conflicts = vec![];
+        for (index, tid) in order {
+            let access_set = match self.engines[tid].next_access(self.timeout) {
+                Ok(Some(access_set)) => access_set,
+                Ok(None) => {
+                    self.fallback = Some(Fallback::EventFailed {
+                        event: index,
+                        transaction: self.transaction_position(index),
+                    });
+                    break;
+                }
+                // left to `stop`
+                Err(_) => return conflicts,
+            };
+            conflicts.extend(replay.step(index, tid, &access_set, &self.migrations));
+            if conflicts.len() > threshold {
+                self.fallback = Some(Fallback::Aborted {
+                    event: index,
+                    transaction: self.transaction_position(index),
+                });
+                break;
+            }
+        }
+        if self.fallback.is_some() {
+            for engine in &self.engines {
+                engine.abort();
+            }
+        }
+        conflicts
     }
 
-    pub fn state_root(&self) -> &H256 {
-        &self.state_root
+    /// Fills in the owners and transaction positions of the conflicts.
+    fn annotate(&self, mut conflicts: Vec<Conflict>) -> Vec<Conflict> {
+        for conflict in &mut conflicts {
+            conflict.owner = self.scheduler.owner(&conflict.key.address());
+            conflict.write_transaction = self.transaction_position(conflict.write_index);
+            conflict.transaction = self.transaction_position(conflict.index);
+        }
+        conflicts
     }
 
-    pub fn set_root(&mut self, root: H256) {
-        self.state_root = root;
+    /// Position of the event at `index` among the transactions of the
+    /// block, `None` if it is not a transaction.
+    fn transaction_position(&self, index: usize) -> Option<usize> {
+        match self.events.get(index) {
+            Some(ExecutionEvent::Transact(_)) => Some(
+                self.events[..index]
+                    .iter()
+                    .filter(|event| match event {
+                        ExecutionEvent::Transact(_) => true,
+                        _ => false,
+                    })
+                    .count(),
+            ),
+            _ => None,
+        }
     }
 
-    pub fn stop(&mut self) -> bool {
-        let mut data_races = self.engines.is_empty();
-        while let Some(engine) = self.engines.pop() {
-            let engine_number = self.engines.len();
-            let (state, internal_address) = engine.stop();
-            if data_races {
-                continue;
+    /// Checks every transaction run by the engines against the block gas
+    /// limit at its block position, as the executive would have.
+    fn within_gas_limit(&self) -> bool {
+        let mut env_info = self.chain.env_info();
+        for (index, event) in self.events.iter().enumerate() {
+            match event {
+                ExecutionEvent::Transact(tx) => {
+                    if env_info.gas_used + tx.gas > env_info.gas_limit {
+                        return false;
+                    }
+                    if let Ok(i) = self
+                        .engine_receipts
+                        .binary_search_by_key(&index, |(i, _)| *i)
+                    {
+                        env_info.gas_used = env_info.gas_used + self.engine_receipts[i].1.gas_used;
+                    }
+                }
+                ExecutionEvent::ChangeEnv(env) => env_info = env.clone(),
+                _ => (),
+            }
+        }
+        true
+    }
+
+    /// A contract whose storage was written by several engines is committed
+    /// by one of them, preferably the one which wrote the account itself.
+    /// Slots written by the other engines are taken out of their states and
+    /// merged on top after commit.
+    fn split_storage(&mut self, writers: &Writers) -> Result<(), Error> {
+        let mut keepers: HashMap<Address, usize> = HashMap::new();
+        for ((addr, _), tid) in &writers.storage {
+            let keeper = keepers.entry(*addr).or_insert(*tid);
+            if *tid < *keeper {
+                *keeper = *tid;
+            }
+        }
+        for (addr, keeper) in keepers.iter_mut() {
+            if let Some(tid) = writers.accounts.get(addr) {
+                *keeper = *tid;
+            }
+        }
+
+        let mut dropped = HashSet::new();
+        for ((addr, key), tid) in &writers.storage {
+            if keepers[addr] != *tid {
+                let value = self.engine_states[*tid]
+                    .storage_at(addr, key)
+                    .map_err(|e| Error::Commit(e.into()))?;
+                self.storage_merges.push((*addr, *key, value));
+                dropped.insert((*tid, *addr));
             }
-            for addr in internal_address {
-                if let Some(id) = self.dependency_table.get(&addr) {
-                    if id != &engine_number {
-                        data_races = true;
-                        self.engine_states = vec![];
-                        break;
+        }
+        for (tid, addr) in dropped {
+            self.engine_states[tid].drop_account(&addr);
+        }
+        Ok(())
+    }
+
+    /// Credits commute, so an engine which only credited an account has it
+    /// taken out of its state, and the credits of all such engines are
+    /// summed on top of the commit. An engine keeping the account because it
+    /// touched it otherwise has the credits of re-executed events taken back.
+    fn merge_credits(
+        &mut self,
+        accesses: &[(usize, usize, AccessSet)],
+        reexecution: &[usize],
+        drops: &HashSet<(usize, Address)>,
+    ) {
+        let mut touched = HashSet::new();
+        for (index, tid, access_set) in accesses {
+            if reexecution.binary_search(index).is_err() {
+                for addr in access_set.touched() {
+                    touched.insert((*tid, *addr));
+                }
+            }
+        }
+
+        let mut taken = HashSet::new();
+        for (index, tid, access_set) in accesses {
+            let reexecuted = reexecution.binary_search(index).is_ok();
+            for (addr, amount) in &access_set.credits {
+                let kept = touched.contains(&(*tid, *addr)) && !drops.contains(&(*tid, *addr));
+                let merge = self
+                    .balance_merges
+                    .entry(*addr)
+                    .or_insert_with(|| (U256::zero(), U256::zero()));
+                match (kept, reexecuted) {
+                    (true, true) => merge.1 = merge.1 + *amount,
+                    (true, false) => (),
+                    (false, true) => {
+                        taken.insert((*tid, *addr));
+                    }
+                    (false, false) => {
+                        merge.0 = merge.0 + *amount;
+                        taken.insert((*tid, *addr));
                     }
-                } else {
-                    self.dependency_table.insert(addr, engine_number);
                 }
             }
-            self.engine_states.push(state);
         }
+        for (tid, addr) in taken {
+            if !drops.contains(&(tid, addr)) {
+                self.engine_states[tid].drop_account(&addr);
+            }
+        }
+    }
+
+    pub fn apply_engines(&mut self) -> Result<(), Error> {
+        self.terminate_secure();
+        self.commit_engines()?;
+        self.set_receipts(vec![]);
+        Ok(())
+    }
 
-        data_races
+    /// Orders the receipts of the engines and the re-executed transactions
+    /// by event index and accumulates their gas used.
+    fn set_receipts(&mut self, reexecuted: Vec<(usize, Receipt)>) {
+        let mut receipts: Vec<_> = self.engine_receipts.drain(..).collect();
+        receipts.retain(|(index, _)| reexecuted.binary_search_by_key(index, |(i, _)| *i).is_err());
+        receipts.extend(reexecuted);
+        receipts.sort_by_key(|(index, _)| *index);
+        self.receipts =
+            receipts::cumulate(receipts.into_iter().map(|(_, receipt)| receipt).collect());
     }
 
-    pub fn apply_engines(&mut self) {
-        self.secure_engine.terminate();
-        while let Some(mut state) = self.engine_states.pop() {
+    fn commit_engines(&mut self) -> Result<(), Error> {
+        let states: Vec<_> = self
+            .engine_states
+            .drain(..)
+            .zip(self.engine_accounts.drain(..))
+            .collect();
+        if states.len() > 1 {
+            commit_parallel(
+                &mut self.state_db,
+                &mut self.state_root,
+                &self.factories,
+                states,
+            )?;
+        } else {
+            for (mut state, _) in states {
+                state
+                    .commit_external(&mut self.state_db, &mut self.state_root, true)
+                    .map_err(|e| Error::Commit(e.into()))?;
+            }
+        }
+        if !self.storage_merges.is_empty() || !self.balance_merges.is_empty() {
+            let mut state = self.state();
+            for (addr, key, value) in self.storage_merges.drain(..) {
+                state
+                    .set_storage(&addr, key, value)
+                    .map_err(|e| Error::Commit(e.into()))?;
+            }
+            for (addr, (credit, debit)) in self.balance_merges.drain() {
+                if credit > debit {
+                    state
+                        .add_balance(&addr, &(credit - debit), CleanupMode::NoEmpty)
+                        .map_err(|e| Error::Commit(e.into()))?;
+                } else if debit > credit {
+                    state
+                        .sub_balance(&addr, &(debit - credit), &mut CleanupMode::NoEmpty)
+                        .map_err(|e| Error::Commit(e.into()))?;
+                }
+            }
             state
                 .commit_external(&mut self.state_db, &mut self.state_root, true)
-                .unwrap();
+                .map_err(|e| Error::Commit(e.into()))?;
         }
+        Ok(())
     }
 
-    pub fn apply_secure(&mut self) {
-        let mut state = self.secure_engine.join();
+    /// Commits the conflict free part of the engine states after a data
+    /// race, then re-executes the racing events and the events depending on
+    /// them in block order on top. Falls back to the secure engine if no
+    /// events could be selected or the re-execution fails.
+    pub fn apply_selective(&mut self) -> Result<(), Error> {
+        let reexecution = match self.reexecution.take() {
+            Some(reexecution) => reexecution,
+            None => return self.apply_secure(),
+        };
+        let root = self.state_root.clone();
+        self.commit_engines()?;
+
+        let mut state = self.state();
+        let mut env_info = self.chain.env_info();
+        let machine = self.chain.machine.clone();
+        let mut reexecuted = vec![];
+        for (index, event) in self.events.iter().enumerate() {
+            let selected = reexecution.binary_search(&index).is_ok();
+            match event {
+                ExecutionEvent::Transact(tx) if selected => {
+                    match state.apply(&env_info, &machine, tx, false) {
+                        Ok(outcome) => {
+                            let gas_used = outcome.receipt.gas_used;
+                            reexecuted.push((index, own_gas_receipt(outcome.receipt, &env_info)));
+                            env_info.gas_used = gas_used;
+                        }
+                        Err(_) => {
+                            self.state_root = root;
+                            self.fallback = Some(Fallback::Reexecution {
+                                event: index,
+                                transaction: self.transaction_position(index),
+                            });
+                            return self.apply_secure();
+                        }
+                    }
+                }
+                ExecutionEvent::Transact(_) => {
+                    // kept from the engines
+                    if let Ok(i) = self
+                        .engine_receipts
+                        .binary_search_by_key(&index, |(i, _)| *i)
+                    {
+                        env_info.gas_used = env_info.gas_used + self.engine_receipts[i].1.gas_used;
+                    }
+                }
+                ExecutionEvent::AddBalance(addr, amount) if selected => {
+                    if state
+                        .add_balance(addr, amount, CleanupMode::NoEmpty)
+                        .is_err()
+                    {
+                        self.state_root = root;
+                        self.fallback = Some(Fallback::Reexecution {
+                            event: index,
+                            transaction: self.transaction_position(index),
+                        });
+                        return self.apply_secure();
+                    }
+                }
+                ExecutionEvent::ChangeEnv(env) => env_info = env.clone(),
+                _ => (),
+            }
+        }
         state
             .commit_external(&mut self.state_db, &mut self.state_root, true)
-            .unwrap();
+            .map_err(|e| Error::Commit(e.into()))?;
+        self.terminate_secure();
+        self.set_receipts(reexecuted);
+        Ok(())
+    }
+
+    /// Drops the secure engine's run of a block applied from the engines.
+    /// Its worker dying doesn't matter to the block, the next run spawns a
+    /// new one.
+    fn terminate_secure(&mut self) {
+        if let Err(e) = self.secure_engine.terminate() {
+            warn!("secure engine lost: {}", e);
+        }
+    }
+
+    /// Applies the block as executed by the secure engine, running it now
+    /// unless it ran alongside the engines.
+    pub fn apply_secure(&mut self) -> Result<(), Error> {
+        if self.secure_policy == SecurePolicy::Disabled {
+            return Err(Error::SecureDisabled);
+        }
+        self.secure_engine.run()?;
         self.engine_states = vec![];
+        self.storage_merges = vec![];
+        self.balance_merges = HashMap::new();
+        self.reexecution = None;
+        self.engine_receipts = vec![];
+        self.receipts = vec![];
+        let (mut state, receipts) = self.secure_engine.join()?;
+        state
+            .commit_external(&mut self.state_db, &mut self.state_root, true)
+            .map_err(|e| Error::Commit(e.into()))?;
+        self.receipts = receipts::cumulate(receipts);
+        Ok(())
+    }
+
+    /// Applies the block by optimistic execution on `threads` workers and
+    /// validation in block order, see `optimistic::apply_block`. Returns the
+    /// number of re-executed transactions.
+    pub fn apply_optimistic(&mut self, threads: usize) -> Result<usize, Error> {
+        let (receipts, reexecuted) = optimistic::apply_block(
+            &mut self.state_db,
+            &mut self.state_root,
+            &self.factories,
+            &self.chain,
+            &self.events,
+            threads,
+        )?;
+        self.receipts = receipts::cumulate(receipts);
+        Ok(reexecuted)
+    }
+
+    /// Why the block was applied from the secure engine rather than the
+    /// engines, `None` if the engines' result was used.
+    pub fn fallback(&self) -> Option<&Fallback> {
+        self.fallback.as_ref()
+    }
+
+    /// Receipts of the applied block in block order, with cumulative gas
+    /// used as in `sequential_exec`.
+    pub fn receipts(&self) -> &Vec<Receipt> {
+        &self.receipts
+    }
+
+    /// Total gas used by the applied block.
+    pub fn gas_used(&self) -> U256 {
+        self.receipts
+            .last()
+            .map_or_else(U256::zero, |receipt| receipt.gas_used)
+    }
+
+    pub fn receipts_root(&self) -> H256 {
+        receipts::receipts_root(&self.receipts)
+    }
+
+    pub fn log_bloom(&self) -> Bloom {
+        receipts::log_bloom(&self.receipts)
     }
 
     pub fn drop(self) -> State<StateDB> {
@@ -260,53 +1190,693 @@ impl ParallelManager {
     }
 }
 
+/// Share of the transactions and balance increments touching an account
+/// already touched by an earlier event of the block.
+fn dependency_rate(events: &[ExecutionEvent]) -> f64 {
+    let mut seen = HashSet::new();
+    let mut dependent = 0;
+    let mut total = 0;
+    for event in events {
+        let addrs = match event {
+            ExecutionEvent::Transact(tx) => match tx.action {
+                Action::Call(to) => vec![tx.sender(), to],
+                Action::Create => vec![tx.sender()],
+            },
+            ExecutionEvent::AddBalance(addr, _) => vec![*addr],
+            _ => continue,
+        };
+        total += 1;
+        if addrs.iter().any(|addr| seen.contains(addr)) {
+            dependent += 1;
+        }
+        seen.extend(addrs);
+    }
+    if total == 0 {
+        return 0.0;
+    }
+    dependent as f64 / total as f64
+}
+
+/// Engines holding the latest write of each account and storage slot.
+#[derive(Default)]
+struct Writers {
+    accounts: HashMap<Address, usize>,
+    storage: HashMap<(Address, H256), usize>,
+}
+
+/// Replays the recorded accesses in block order. A data race happened if
+/// an engine touched an account or a storage slot whose latest write lives
+/// in another engine's state, or which another engine credited before,
+/// unless the account cache was migrated in between. Returns the final
+/// writers and every conflicting access, the block is free of races if
+/// there is none.
+fn replay_accesses(
+    accesses: &[(usize, usize, AccessSet)],
+    migrations: &[(usize, Address, usize, usize)],
+) -> (Writers, Vec<Conflict>) {
+    let mut replay = Replay::default();
+    let mut conflicts = vec![];
+    for (index, tid, access_set) in accesses {
+        conflicts.extend(replay.step(*index, *tid, access_set, migrations));
+    }
+    (replay.writers, conflicts)
+}
+
+/// Replay of the accesses one event at a time, in block order.
+#[derive(Default)]
+struct Replay {
+    writers: Writers,
+    // event index of the latest write of each key
+    write_indices: HashMap<StateKey, usize>,
+    // engines crediting each account, with the index of their latest credit
+    creditors: HashMap<Address, HashMap<usize, usize>>,
+    // number of migrations replayed
+    migrated: usize,
+}
+
+impl Replay {
+    /// Replays the accesses of the event at `index` run on `tid`, returning
+    /// its conflicts.
+    fn step(
+        &mut self,
+        index: usize,
+        tid: usize,
+        access_set: &AccessSet,
+        migrations: &[(usize, Address, usize, usize)],
+    ) -> Vec<Conflict> {
+        while let Some((_, addr, from, to)) = migrations.get(self.migrated).filter(|m| m.0 <= index)
+        {
+            if self.writers.accounts.get(addr) == Some(from) {
+                self.writers.accounts.insert(*addr, *to);
+            }
+            if let Some(engines) = self.creditors.get_mut(addr) {
+                if let Some(credit_index) = engines.remove(from) {
+                    engines.insert(*to, credit_index);
+                }
+            }
+            for ((slot_addr, _), writer) in self.writers.storage.iter_mut() {
+                if slot_addr == addr && *writer == *from {
+                    *writer = *to;
+                }
+            }
+            self.migrated += 1;
+        }
+        let conflict = |key: StateKey, writer: usize, write_index: usize| Conflict {
+            key: key,
+            owner: None,
+            writer: writer,
+            write_index: write_index,
+            write_transaction: None,
+            engine: tid,
+            index: index,
+            transaction: None,
+        };
+        let mut conflicts = vec![];
+        for addr in access_set.touched() {
+            let key = StateKey::Account(*addr);
+            match self.writers.accounts.get(addr) {
+                Some(writer) if *writer != tid => {
+                    conflicts.push(conflict(key, *writer, self.write_indices[&key]));
+                }
+                _ => (),
+            }
+            if let Some(engines) = self.creditors.get(addr) {
+                for (creditor, credit_index) in engines {
+                    if *creditor != tid {
+                        conflicts.push(conflict(key, *creditor, *credit_index));
+                    }
+                }
+            }
+        }
+        for (addr, slot) in access_set.touched_storage() {
+            let key = StateKey::Storage(*addr, *slot);
+            match self.writers.storage.get(&(*addr, *slot)) {
+                Some(writer) if *writer != tid => {
+                    conflicts.push(conflict(key, *writer, self.write_indices[&key]));
+                }
+                _ => (),
+            }
+        }
+        for addr in &access_set.writes {
+            self.writers.accounts.insert(*addr, tid);
+        }
+        for slot in &access_set.storage_writes {
+            self.writers.storage.insert(*slot, tid);
+        }
+        for key in access_set.written_keys() {
+            self.write_indices.insert(key, index);
+        }
+        for addr in access_set.credits.keys() {
+            self.creditors.entry(*addr).or_default().insert(tid, index);
+        }
+        conflicts
+    }
+}
+
+/// Picks the events to re-execute after a data race, in block order:
+/// - events touching state last written by another engine,
+/// - events touching state written by an event already picked,
+/// - events touching an account credited by another engine or by a picked
+///   event,
+/// - events writing or crediting state touched by an earlier picked event,
+///   which would otherwise be seen by its re-execution,
+/// - events writing an account which is dropped from their engine's state
+///   because a picked event wrote it there,
+/// - the `stale` events, and the events depending on them.
+///
+/// Returns the picked events and the accounts to drop from each engine's
+/// state, or `None` if a dropped account was migrated between engines.
+fn select_reexecution(
+    accesses: &[(usize, usize, AccessSet)],
+    migrations: &[(usize, Address, usize, usize)],
+    stale: &[usize],
+) -> Option<(Vec<usize>, HashSet<(usize, Address)>)> {
+    let mut selected: HashSet<usize> = stale.iter().cloned().collect();
+    let mut drops = HashSet::new();
+    loop {
+        let mut changed = false;
+        let mut migration_iter = migrations.iter().peekable();
+        let mut writers: HashMap<StateKey, (usize, usize)> = HashMap::new();
+        let mut creditors: HashMap<Address, Vec<(usize, usize)>> = HashMap::new();
+        let mut selected_touches = HashSet::new();
+        for (index, tid, access_set) in accesses {
+            while let Some((_, addr, from, to)) = migration_iter.peek().filter(|m| m.0 <= *index) {
+                for (key, (writer, _)) in writers.iter_mut() {
+                    if key.address() == *addr && *writer == *from {
+                        *writer = *to;
+                    }
+                }
+                for (creditor, _) in creditors.entry(*addr).or_default().iter_mut() {
+                    if *creditor == *from {
+                        *creditor = *to;
+                    }
+                }
+                migration_iter.next();
+            }
+            let touched = access_set.touched_keys();
+            let written = access_set.written_keys();
+            let depends = |writer: &usize, writer_index: &usize| {
+                writer != tid || selected.contains(writer_index)
+            };
+            let is_selected = selected.contains(index)
+                || touched.iter().any(|key| match writers.get(key) {
+                    Some((writer, writer_index)) => depends(writer, writer_index),
+                    None => false,
+                })
+                || access_set.touched().any(|addr| match creditors.get(addr) {
+                    Some(credits) => credits.iter().any(|(c, i)| depends(c, i)),
+                    None => false,
+                })
+                || written.iter().any(|key| {
+                    selected_touches.contains(key) || drops.contains(&(*tid, key.address()))
+                })
+                || access_set
+                    .credits
+                    .keys()
+                    .any(|addr| selected_touches.contains(&StateKey::Account(*addr)));
+            if is_selected {
+                changed |= selected.insert(*index);
+                for key in &written {
+                    changed |= drops.insert((*tid, key.address()));
+                }
+                selected_touches.extend(touched);
+            }
+            for key in written {
+                if let StateKey::Account(addr) = key {
+                    creditors.remove(&addr);
+                }
+                writers.insert(key, (*tid, *index));
+            }
+            for addr in access_set.credits.keys() {
+                creditors.entry(*addr).or_default().push((*tid, *index));
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    if migrations
+        .iter()
+        .any(|(_, addr, _, _)| drops.iter().any(|(_, dropped)| dropped == addr))
+    {
+        return None;
+    }
+    let mut selected: Vec<usize> = selected.into_iter().collect();
+    selected.sort();
+    Some((selected, drops))
+}
+
 #[cfg(test)]
 mod tests {
     extern crate env_logger;
     use super::*;
     use crate::execution_engine::sequential_exec;
-    use crate::test_helpers;
-    use ethcore::open_state::CleanupMode;
+    use crate::test_helpers::{self, transfer};
+    use common_types::transaction::Transaction;
+    use ethstore::ethkey::{Generator, Random};
     use std::io::Write;
 
+    #[test]
+    fn test_data_races() {
+        let (a, b) = (Address::from(1), Address::from(2));
+        let mut write_a = AccessSet::default();
+        write_a.write(a);
+        let mut read_a = AccessSet::default();
+        read_a.read(a);
+        read_a.write(b);
+
+        // engine 1 reads `a` before engine 0 writes it
+        let accesses = vec![(0, 1, read_a.clone()), (1, 0, write_a.clone())];
+        assert!(replay_accesses(&accesses, &[]).1.is_empty());
+
+        // engine 1 reads `a` after engine 0 wrote it
+        let accesses = vec![(0, 0, write_a.clone()), (1, 1, read_a.clone())];
+        let (_, conflicts) = replay_accesses(&accesses, &[]);
+        assert_eq!(
+            conflicts,
+            vec![Conflict {
+                key: StateKey::Account(a),
+                owner: None,
+                writer: 0,
+                write_index: 0,
+                write_transaction: None,
+                engine: 1,
+                index: 1,
+                transaction: None,
+            }]
+        );
+
+        // `a` was migrated from engine 0 to engine 1 in between
+        let accesses = vec![(0, 0, write_a.clone()), (1, 1, read_a.clone())];
+        assert!(replay_accesses(&accesses, &[(1, a, 0, 1)]).1.is_empty());
+    }
+
+    #[test]
+    fn test_storage_data_races() {
+        let token = Address::from(1);
+        let (key_a, key_b) = (H256::from(1), H256::from(2));
+        let mut write_a = AccessSet::default();
+        write_a.write_storage(token, key_a);
+        let mut write_b = AccessSet::default();
+        write_b.write_storage(token, key_b);
+        let mut read_a = AccessSet::default();
+        read_a.read_storage(token, key_a);
+
+        // different slots of one contract
+        let accesses = vec![(0, 0, write_a.clone()), (1, 1, write_b.clone())];
+        let (writers, conflicts) = replay_accesses(&accesses, &[]);
+        assert!(conflicts.is_empty());
+        assert_eq!(writers.storage[&(token, key_a)], 0);
+        assert_eq!(writers.storage[&(token, key_b)], 1);
+
+        // same slot read after written by another engine
+        let accesses = vec![(0, 0, write_a.clone()), (1, 1, read_a.clone())];
+        let (_, conflicts) = replay_accesses(&accesses, &[]);
+        assert_eq!(conflicts[0].key, StateKey::Storage(token, key_a));
+    }
+
     #[test]
     fn test_static_dependency_100_4() {
-        let transactions = test_helpers::static_dep_txs(50, 100, true);
+        let transactions = test_helpers::static_dep_txs(50, 100, false);
         test_static_dependency(&transactions, 4);
     }
 
-    fn test_static_dependency(transactions: &Vec<SignedTransaction>, engines: usize) {
-        init("SD");
-        // Set up state db
-        let mut state = test_helpers::get_temp_state();
+    #[test]
+    fn test_commutative_credits() {
+        let (author, a, b) = (Address::from(1), Address::from(2), Address::from(3));
+        let pay_fee = |sender| {
+            let mut access_set = AccessSet::default();
+            access_set.write(sender);
+            access_set.credit(author, U256::from(21000));
+            access_set
+        };
+        let mut read_author = AccessSet::default();
+        read_author.read(author);
 
-        for tx in transactions {
-            state
-                .add_balance(&tx.sender(), &U256::from(1), CleanupMode::NoEmpty)
-                .unwrap();
+        // engines crediting the author in turn
+        let accesses = vec![(0, 0, pay_fee(a)), (1, 1, pay_fee(b))];
+        assert!(replay_accesses(&accesses, &[]).1.is_empty());
+
+        // an engine reading the author misses the other engine's credit
+        let accesses = vec![(0, 0, pay_fee(a)), (1, 1, read_author)];
+        assert!(!replay_accesses(&accesses, &[]).1.is_empty());
+    }
+
+    #[test]
+    fn test_paid_transfers() {
+        // every engine credits the fees to the block author
+        let transactions: Vec<_> = (0..8)
+            .map(|_| {
+                Transaction {
+                    action: Action::Call(Address::random()),
+                    value: U256::from(1),
+                    data: vec![],
+                    gas: U256::from(21_000),
+                    gas_price: U256::from(2),
+                    nonce: U256::zero(),
+                }
+                .sign(Random.generate().unwrap().secret(), None)
+            })
+            .collect();
+        let state = test_helpers::funded_state(&transactions, U256::from(100_000));
+        let expected = test_helpers::sequential_root(&state, &transactions, &Chain::default());
+
+        let mut parallel_manager = ParallelManager::new(state);
+        parallel_manager.add_engines(4);
+        parallel_manager.add_transactions(transactions);
+        let report = test_helpers::run_block(&mut parallel_manager).unwrap();
+        assert!(!report.race);
+        assert_eq!(&expected, parallel_manager.state_root());
+        let author = Chain::default().env_info().author;
+        assert_eq!(
+            parallel_manager.state().balance(&author).unwrap(),
+            U256::from(8 * 21_000 * 2)
+        );
+    }
+
+    #[test]
+    fn test_select_reexecution() {
+        let (a, b, c) = (Address::from(1), Address::from(2), Address::from(3));
+        let write = |addr| {
+            let mut access_set = AccessSet::default();
+            access_set.write(addr);
+            access_set
+        };
+        let mut read_a_write_b = write(b);
+        read_a_write_b.read(a);
+        let mut read_b = AccessSet::default();
+        read_b.read(b);
+
+        let accesses = vec![
+            (0, 0, write(a)),
+            (1, 1, read_a_write_b),
+            (2, 1, read_b),
+            (3, 0, write(c)),
+        ];
+        let (selected, drops) = select_reexecution(&accesses, &[], &[]).unwrap();
+        // 1 read `a` from another engine, 2 depends on 1
+        assert_eq!(selected, vec![1, 2]);
+        assert!(drops.contains(&(1, b)));
+        assert!(!drops.contains(&(0, a)));
+    }
+
+    #[test]
+    fn test_engine_pool_reuse() {
+        let transactions = test_helpers::static_dep_txs(50, 100, false);
+        let state = test_helpers::funded_state(&transactions, U256::from(1));
+
+        let mut parallel_manager = ParallelManager::new(state.clone());
+        parallel_manager.add_transactions(transactions.clone());
+        let mut roots = vec![];
+        for _ in 0..2 {
+            parallel_manager.set_state(state.clone());
+            parallel_manager.add_engines(4);
+            test_helpers::run_block(&mut parallel_manager).unwrap();
+            roots.push(parallel_manager.root());
         }
-        state.commit().unwrap();
+        assert_eq!(parallel_manager.pool.len(), 4);
+        parallel_manager.shutdown().unwrap();
+
+        assert_eq!(roots[0], roots[1]);
+    }
+
+    #[test]
+    fn test_engine_receipts() {
+        let contract = Address::random();
+        let transactions = logging_transactions(contract);
+        let state = logging_state(&transactions, contract);
+        let mut expected = state.clone();
+        let receipts = sequential_exec(&mut expected, &transactions, &Chain::default()).unwrap();
 
-        // initiate PM
         let mut parallel_manager = ParallelManager::new(state);
-        parallel_manager.add_engines(engines);
+        parallel_manager.add_engines(4);
+        parallel_manager.add_transactions(transactions);
+        test_helpers::run_block(&mut parallel_manager).unwrap();
+        assert!(parallel_manager.fallback().is_none());
+        assert_eq!(&receipts, parallel_manager.receipts());
+        assert_eq!(
+            receipts::receipts_root(&receipts),
+            parallel_manager.receipts_root()
+        );
+        assert_eq!(receipts::log_bloom(&receipts), parallel_manager.log_bloom());
+        assert!(!parallel_manager.log_bloom().is_empty());
+    }
+
+    #[test]
+    fn test_intermediate_roots() {
+        let chain = Chain::from_name("homestead").unwrap();
+        let contract = Address::random();
+        let transactions = logging_transactions(contract);
+        let state = logging_state(&transactions, contract);
+        let mut expected = state.clone();
+        let receipts = sequential_exec(&mut expected, &transactions, &chain).unwrap();
+        expected.commit().unwrap();
+
+        let mut parallel_manager = ParallelManager::with_chain(state, chain);
+        parallel_manager.add_engines(4);
+        parallel_manager.add_transactions(transactions);
+        let report = test_helpers::run_block(&mut parallel_manager).unwrap();
+        assert!(report.race);
+        match parallel_manager.fallback() {
+            Some(Fallback::IntermediateRoots) => (),
+            _ => panic!("engine receipts carry intermediate roots"),
+        }
+        assert_eq!(&receipts, parallel_manager.receipts());
+        assert_eq!(
+            receipts::receipts_root(&receipts),
+            parallel_manager.receipts_root()
+        );
+        assert_eq!(expected.root(), parallel_manager.state_root());
+    }
+
+    #[test]
+    fn test_invalid_transaction() {
+        let transactions = test_helpers::static_dep_txs(50, 100, false);
+        let state = test_helpers::funded_state(&transactions, U256::from(1));
+        // replays the nonce of the first transaction
+        let mut replayed = transactions.clone();
+        replayed.push(transactions[0].clone());
+
+        let mut parallel_manager = ParallelManager::new(state);
+        parallel_manager.add_engines(4);
+        parallel_manager.add_env_info(Chain::default().env_info());
+        parallel_manager.add_transactions(replayed);
+        parallel_manager.clone_to_secure();
+        parallel_manager.consume().unwrap();
+        assert!(parallel_manager.stop().unwrap().race);
+        // the event index counts the env change, the position only the
+        // transactions
+        match parallel_manager.fallback() {
+            Some(Fallback::EventFailed { event, transaction }) => {
+                assert_eq!(*event, transactions.len() + 1);
+                assert_eq!(*transaction, Some(transactions.len()));
+            }
+            _ => panic!("the replayed transaction fails on its engine"),
+        }
+        match parallel_manager.apply_selective() {
+            Err(Error::Execution(index, _)) => assert_eq!(index, transactions.len()),
+            _ => panic!("the replayed transaction is invalid"),
+        }
+    }
+
+    /// Ignores dependencies, every race is left to the data race check.
+    #[derive(Clone)]
+    struct RoundRobin(usize);
+
+    impl Scheduler for RoundRobin {
+        fn reset(&mut self, engines: usize) {
+            self.0 = engines;
+        }
+
+        fn schedule(
+            &mut self,
+            index: usize,
+            _event: &ExecutionEvent,
+            _state: &State<StateDB>,
+        ) -> Option<Assignment> {
+            Some(Assignment {
+                engine: index % self.0,
+                migrations: vec![],
+            })
+        }
+
+        fn owner(&self, _addr: &Address) -> Option<usize> {
+            None
+        }
+
+        fn boxed_clone(&self) -> Box<dyn Scheduler> {
+            Box::new(self.clone())
+        }
+    }
+
+    #[test]
+    fn test_custom_scheduler() {
+        let transactions = test_helpers::static_dep_txs(50, 100, false);
+        let state = test_helpers::funded_state(&transactions, U256::from(1));
+        let expected = test_helpers::sequential_root(&state, &transactions, &Chain::default());
+
+        let mut parallel_manager = ParallelManager::new(state);
+        parallel_manager.set_scheduler(Box::new(RoundRobin(0)));
+        parallel_manager.add_engines(4);
         parallel_manager.add_transactions(transactions.clone());
+        test_helpers::run_block(&mut parallel_manager).unwrap();
+        assert_eq!(parallel_manager.dispatched[1][0], 1);
+        assert_eq!(&expected, parallel_manager.state_root());
+    }
+
+    #[test]
+    fn test_block_gas_limit() {
+        // each engine's own transaction fits, the third one doesn't at its
+        // block position
+        let transactions: Vec<_> = (0..3)
+            .map(|_| transfer(&Random.generate().unwrap(), Address::random(), 21_000))
+            .collect();
+        let state = test_helpers::funded_state(&transactions, U256::from(1));
+        let mut env_info = Chain::default().env_info();
+        env_info.gas_limit = U256::from(50_000);
+
+        let mut parallel_manager = ParallelManager::new(state);
+        parallel_manager.set_scheduler(Box::new(RoundRobin(0)));
+        parallel_manager.add_engines(3);
+        parallel_manager.add_env_info(env_info);
+        parallel_manager.add_transactions(transactions);
         parallel_manager.clone_to_secure();
-        parallel_manager.consume();
-        parallel_manager.stop();
+        parallel_manager.consume().unwrap();
+        assert!(parallel_manager.stop().unwrap().race);
+        match parallel_manager.fallback() {
+            Some(Fallback::GasLimitExceeded) => (),
+            _ => panic!("the block exceeds its gas limit"),
+        }
+        match parallel_manager.apply_selective() {
+            Err(Error::Execution(index, _)) => assert_eq!(index, 2),
+            _ => panic!("the third transaction is over the gas limit"),
+        }
+    }
 
-        // Sequential execution
-        let mut state = test_helpers::get_temp_state();
-        for tx in transactions {
-            state
-                .add_balance(&tx.sender(), &U256::from(1), CleanupMode::NoEmpty)
-                .unwrap();
+    #[test]
+    fn test_early_abort() {
+        let (a, c) = (Random.generate().unwrap(), Random.generate().unwrap());
+        let b = Address::random();
+        // both pay `b`, each on its own engine
+        let transactions = vec![transfer(&a, b, 21_000), transfer(&c, b, 21_000)];
+        let state = test_helpers::funded_state(&transactions, U256::from(1));
+        let expected = test_helpers::sequential_root(&state, &transactions, &Chain::default());
+
+        let mut parallel_manager = ParallelManager::new(state);
+        parallel_manager.set_scheduler(Box::new(RoundRobin(0)));
+        parallel_manager.set_abort_threshold(Some(0));
+        parallel_manager.add_engines(2);
+        parallel_manager.add_transactions(transactions.clone());
+        parallel_manager.clone_to_secure();
+        parallel_manager.consume().unwrap();
+        let report = parallel_manager.stop().unwrap();
+        assert!(report.race);
+        assert_eq!(report.conflicts[0].key, StateKey::Account(b));
+        assert_eq!(report.conflicts[0].index, 1);
+        assert_eq!(report.conflicts[0].write_transaction, Some(0));
+        assert_eq!(report.conflicts[0].transaction, Some(1));
+        match parallel_manager.fallback() {
+            Some(Fallback::Aborted {
+                event: 1,
+                transaction: Some(1),
+            }) => (),
+            _ => panic!("the engines are aborted at the first conflict"),
         }
+        parallel_manager.apply_selective().unwrap();
+        assert_eq!(&expected, parallel_manager.state_root());
+    }
 
-        sequential_exec(&mut state, &transactions);
-        state.commit().unwrap();
+    #[test]
+    fn test_lazy_secure_engine() {
+        let transactions = test_helpers::static_dep_txs(50, 100, false);
+        let state = test_helpers::funded_state(&transactions, U256::from(1));
+        let expected = test_helpers::sequential_root(&state, &transactions, &Chain::default());
+
+        let mut parallel_manager = ParallelManager::new(state.clone());
+        parallel_manager.set_secure_policy(SecurePolicy::Lazy);
+        parallel_manager.add_transactions(transactions.clone());
+        // no engines, the block is left to the secure engine
+        parallel_manager.clone_to_secure();
+        parallel_manager.consume().unwrap();
+        assert!(!parallel_manager.secure_engine.is_running());
+        assert!(parallel_manager.stop().unwrap().race);
+        parallel_manager.apply_selective().unwrap();
+        assert_eq!(&expected, parallel_manager.state_root());
+        assert!(parallel_manager.race_rate() > 0.0);
+
+        parallel_manager.set_state(state);
+        parallel_manager.set_secure_policy(SecurePolicy::Disabled);
+        parallel_manager.clone_to_secure();
+        parallel_manager.consume().unwrap();
+        parallel_manager.stop().unwrap();
+        match parallel_manager.apply_selective() {
+            Err(Error::SecureDisabled) => (),
+            _ => panic!("no secure engine to fall back to"),
+        }
+        parallel_manager.shutdown().unwrap();
 
-        assert_eq!(state.root(), parallel_manager.state_root());
+        // chained transfers only
+        assert!(dependency_rate(&parallel_manager.events) > 0.0);
+    }
+
+    #[test]
+    fn test_stalled_migration() {
+        let state = test_helpers::get_temp_state();
+        let timeout = Duration::from_millis(10);
+        let engine = ExecutionEngine::start(state, 0, Chain::default(), timeout);
+        // no engine ever sends the account
+        engine.wait_cache(vec![Address::from(1)]).unwrap();
+        match engine.stop(DEFAULT_TIMEOUT) {
+            Err(Error::Stalled(_, addr)) => assert_eq!(addr, Address::from(1)),
+            _ => panic!("the engine gives up waiting"),
+        }
+        engine.shutdown().unwrap();
+    }
+
+    fn test_static_dependency(transactions: &Vec<SignedTransaction>, engines: usize) {
+        init("SD");
+        let state = test_helpers::funded_state(transactions, U256::from(1));
+        let expected = test_helpers::sequential_root(&state, transactions, &Chain::default());
+
+        let mut parallel_manager = ParallelManager::new(state);
+        parallel_manager.add_engines(engines);
+        parallel_manager.add_transactions(transactions.clone());
+        test_helpers::run_block(&mut parallel_manager).unwrap();
+
+        assert_eq!(&expected, parallel_manager.state_root());
+    }
+
+    /// Calls to a contract emitting a log, each followed by a transfer,
+    /// from distinct senders.
+    fn logging_transactions(contract: Address) -> Vec<SignedTransaction> {
+        (0..8)
+            .map(|i| {
+                let sender = Random.generate().unwrap();
+                if i % 2 == 0 {
+                    Transaction {
+                        action: Action::Call(contract),
+                        value: U256::zero(),
+                        data: vec![],
+                        gas: U256::from(100_000),
+                        gas_price: U256::zero(),
+                        nonce: U256::zero(),
+                    }
+                    .sign(sender.secret(), None)
+                } else {
+                    transfer(&sender, Address::random(), 21_000)
+                }
+            })
+            .collect()
+    }
+
+    fn logging_state(transactions: &[SignedTransaction], contract: Address) -> State<StateDB> {
+        let mut state = test_helpers::funded_state(transactions, U256::from(1));
+        // LOG0 of empty data
+        let code = vec![0x60, 0x00, 0x60, 0x00, 0xa0, 0x00];
+        state.init_code(&contract, code).unwrap();
+        state.commit().unwrap();
+        state
     }
 
     fn init(test_name: &'static str) {
@@ -0,0 +1,270 @@
+use crate::error::Error;
+use crate::execution_engine::ExecutionEvent;
+use crate::parallel_manager::ParallelManager;
+use common_types::receipt::Receipt;
+use ethereum_types::H256;
+use std::mem;
+
+/// Result of one block of a `Pipeline`.
+#[derive(Clone, Debug)]
+pub struct BlockResult {
+    pub root: H256,
+    /// Receipts in block order, with cumulative gas used.
+    pub receipts: Vec<Receipt>,
+    /// Whether the engines' result of the block could not be applied as is.
+    pub race: bool,
+    /// Whether the block started before the previous one was applied.
+    pub pipelined: bool,
+}
+
+/// Executes a chain of blocks, starting the engines on each block while the
+/// previous block is applied. Two managers take turns, each keeping its
+/// engines, and the accounts assigned to the engines are carried over from
+/// one block to the next.
+///
+/// A block only starts early if the previous block was free of races, so
+/// that the keys it writes are known. Its events touching those keys are
+/// then re-executed on top of the previous block, see
+/// `ParallelManager::follow`.
+pub struct Pipeline {
+    current: ParallelManager,
+    next: ParallelManager,
+    engines: usize,
+}
+
+impl Pipeline {
+    pub fn new(mut manager: ParallelManager, engines: usize) -> Pipeline {
+        manager.set_carry_over(true);
+        let next = manager.clone();
+        Pipeline {
+            current: manager,
+            next: next,
+            engines: engines,
+        }
+    }
+
+    /// Executes and applies the blocks in order.
+    pub fn run(&mut self, blocks: Vec<Vec<ExecutionEvent>>) -> Result<Vec<BlockResult>, Error> {
+        let mut results = vec![];
+        let mut blocks = blocks.into_iter();
+        let mut upcoming = blocks.next();
+        // whether the current block was started by the previous iteration
+        let mut pipelined = false;
+        while let Some(events) = upcoming.take() {
+            if !pipelined {
+                self.current.set_events(events);
+                start(&mut self.current, self.engines)?;
+            }
+            let report = self.current.stop()?;
+
+            upcoming = blocks.next();
+            let started = match &upcoming {
+                Some(events) if self.next.follow(&self.current) => {
+                    self.next.set_events(events.clone());
+                    start(&mut self.next, self.engines)?;
+                    true
+                }
+                _ => false,
+            };
+
+            if report.race {
+                self.current.apply_selective()?;
+            } else {
+                self.current.apply_engines()?;
+            }
+            results.push(BlockResult {
+                root: self.current.root(),
+                receipts: self.current.receipts().clone(),
+                race: report.race,
+                pipelined: pipelined,
+            });
+
+            if started {
+                self.next.rebase(&self.current);
+                mem::swap(&mut self.current, &mut self.next);
+            }
+            pipelined = started;
+        }
+        Ok(results)
+    }
+
+    /// Manager holding the state after the last applied block.
+    pub fn manager(&self) -> &ParallelManager {
+        &self.current
+    }
+
+    /// Terminates the engines of both managers.
+    pub fn shutdown(&mut self) -> Result<(), Error> {
+        let result = self.current.shutdown();
+        result.and(self.next.shutdown())
+    }
+}
+
+fn start(manager: &mut ParallelManager, engines: usize) -> Result<(), Error> {
+    manager.add_engines(engines);
+    manager.clone_to_secure();
+    manager.consume()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::Chain;
+    use crate::execution_engine::sequential_exec;
+    use crate::test_helpers::{self, transfer};
+    use common_types::transaction::{Action, Transaction};
+    use ethereum_types::{Address, U256};
+    use ethstore::ethkey::{Generator, KeyPair, Random};
+
+    #[test]
+    fn test_pipeline() {
+        let senders: Vec<_> = (0..4).map(|_| Random.generate().unwrap()).collect();
+        let (b, e) = (Address::random(), Address::random());
+        let blocks = vec![
+            vec![transfer(&senders[0], b, 21_000)],
+            // `b` is written by the first block, `e` is not
+            vec![
+                transfer(&senders[1], b, 21_000),
+                transfer(&senders[2], e, 21_000),
+            ],
+            // the second block raced, so this one waits for it
+            vec![transfer(&senders[3], e, 21_000)],
+        ];
+        let all: Vec<_> = blocks.iter().flatten().cloned().collect();
+        let state = test_helpers::funded_state(&all, U256::from(1));
+
+        let mut expected = state.clone();
+        let mut expected_roots = vec![];
+        for transactions in &blocks {
+            sequential_exec(&mut expected, transactions, &Chain::default()).unwrap();
+            expected.commit().unwrap();
+            expected_roots.push(expected.root().clone());
+        }
+
+        let mut pipeline = Pipeline::new(ParallelManager::new(state), 2);
+        let events = blocks
+            .into_iter()
+            .map(|transactions| {
+                transactions
+                    .into_iter()
+                    .map(ExecutionEvent::Transact)
+                    .collect()
+            })
+            .collect();
+        let results = pipeline.run(events).unwrap();
+        let roots: Vec<_> = results.iter().map(|result| result.root).collect();
+        assert_eq!(roots, expected_roots);
+        assert!(!results[0].race);
+        assert!(results[1].pipelined && results[1].race);
+        assert!(!results[2].pipelined);
+        assert_eq!(results[1].receipts.len(), 2);
+        pipeline.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_pipeline_fees() {
+        // every transaction pays its fee to the same author
+        let paid = |to| {
+            Transaction {
+                action: Action::Call(to),
+                value: U256::from(1),
+                data: vec![],
+                gas: U256::from(21_000),
+                gas_price: U256::from(2),
+                nonce: U256::zero(),
+            }
+            .sign(Random.generate().unwrap().secret(), None)
+        };
+        let blocks: Vec<Vec<_>> = (0..3)
+            .map(|_| (0..4).map(|_| paid(Address::random())).collect())
+            .collect();
+        let all: Vec<_> = blocks.iter().flatten().cloned().collect();
+        let state = test_helpers::funded_state(&all, U256::from(100_000));
+
+        let mut expected = state.clone();
+        let mut expected_roots = vec![];
+        for transactions in &blocks {
+            sequential_exec(&mut expected, transactions, &Chain::default()).unwrap();
+            expected.commit().unwrap();
+            expected_roots.push(expected.root().clone());
+        }
+
+        let mut pipeline = Pipeline::new(ParallelManager::new(state), 2);
+        let events = blocks
+            .into_iter()
+            .map(|transactions| {
+                transactions
+                    .into_iter()
+                    .map(ExecutionEvent::Transact)
+                    .collect()
+            })
+            .collect();
+        let results = pipeline.run(events).unwrap();
+        let roots: Vec<_> = results.iter().map(|result| result.root).collect();
+        assert_eq!(roots, expected_roots);
+        // the shared author doesn't make the next blocks stale
+        assert!(results.iter().all(|result| !result.race));
+        assert!(results[1].pipelined && results[2].pipelined);
+        let author = Chain::default().env_info().author;
+        assert_eq!(
+            pipeline.manager().state().balance(&author).unwrap(),
+            U256::from(12 * 21_000 * 2)
+        );
+        pipeline.shutdown().unwrap();
+    }
+
+    #[test]
+    fn test_pipeline_storage() {
+        // stores 1 at the slot given in the call data
+        let contract = Address::random();
+        let code = vec![0x60, 0x01, 0x60, 0x00, 0x35, 0x55, 0x00];
+        let store = |sender: &KeyPair, slot: u64| {
+            Transaction {
+                action: Action::Call(contract),
+                value: U256::zero(),
+                data: H256::from(slot).to_vec(),
+                gas: U256::from(100_000),
+                gas_price: U256::zero(),
+                nonce: U256::zero(),
+            }
+            .sign(sender.secret(), None)
+        };
+        let senders: Vec<_> = (0..2).map(|_| Random.generate().unwrap()).collect();
+        // both blocks write the same contract, on different slots
+        let blocks = vec![vec![store(&senders[0], 1)], vec![store(&senders[1], 2)]];
+        let all: Vec<_> = blocks.iter().flatten().cloned().collect();
+        let mut state = test_helpers::funded_state(&all, U256::from(1));
+        state.init_code(&contract, code).unwrap();
+        state.commit().unwrap();
+
+        let mut expected = state.clone();
+        for transactions in &blocks {
+            sequential_exec(&mut expected, transactions, &Chain::default()).unwrap();
+        }
+        expected.commit().unwrap();
+
+        let mut pipeline = Pipeline::new(ParallelManager::new(state), 2);
+        let events = blocks
+            .into_iter()
+            .map(|transactions| {
+                transactions
+                    .into_iter()
+                    .map(ExecutionEvent::Transact)
+                    .collect()
+            })
+            .collect();
+        let results = pipeline.run(events).unwrap();
+        assert!(results[1].pipelined);
+        assert_eq!(&results[1].root, expected.root());
+        let state = pipeline.manager().state();
+        assert_eq!(
+            state.storage_at(&contract, &H256::from(1)).unwrap(),
+            H256::from(1)
+        );
+        assert_eq!(
+            state.storage_at(&contract, &H256::from(2)).unwrap(),
+            H256::from(1)
+        );
+        pipeline.shutdown().unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use crate::execution_engine::ExecutionEvent;
+use crate::shared_cache::{transaction_accounts, BlockCache};
+use ethereum_types::{Address, H256};
+use hashbrown::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// (account, hinted storage slots) to load for the events of a block, in
+/// block order. Senders and receivers are known before execution, storage
+/// slots only if hinted.
+pub fn targets(
+    events: &[ExecutionEvent],
+    hints: &HashMap<Address, Vec<H256>>,
+) -> Vec<(Address, Vec<H256>)> {
+    let mut seen = HashSet::new();
+    let mut targets = vec![];
+    for event in events {
+        for addr in transaction_accounts(event) {
+            if seen.insert(addr) {
+                let keys = hints.get(&addr).cloned().unwrap_or_default();
+                targets.push((addr, keys));
+            }
+        }
+    }
+    targets
+}
+
+/// Threads loading accounts, their code and storage slots from the
+/// database ahead of the engines, so that they are decoded in the shared
+/// cache when a transaction reaches them. Thread `i` of `n` loads the targets
+/// `i`, `i + n`, ..., so the first transactions are warmed first.
+pub struct Prefetcher {
+    stopped: Arc<AtomicBool>,
+    handlers: Vec<JoinHandle<usize>>,
+}
+
+impl Prefetcher {
+    pub fn start(
+        cache: BlockCache,
+        threads: usize,
+        targets: Vec<(Address, Vec<H256>)>,
+    ) -> Prefetcher {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let targets = Arc::new(targets);
+        let threads = threads.max(1);
+        let handlers = (0..threads)
+            .map(|number| {
+                let cache = cache.clone();
+                let stopped = stopped.clone();
+                let targets = targets.clone();
+                thread::Builder::new()
+                    .name(format!("{}{}", "prefetch".to_string(), &number.to_string()))
+                    .spawn(move || {
+                        let mut loaded = 0;
+                        for (addr, keys) in targets.iter().skip(number).step_by(threads) {
+                            if stopped.load(Ordering::Relaxed) {
+                                break;
+                            }
+                            // A failed read is left to the engine to report.
+                            let _ = cache.account(addr);
+                            for key in keys {
+                                let _ = cache.storage_at(addr, key);
+                            }
+                            loaded += 1;
+                        }
+                        loaded
+                    })
+                    .unwrap()
+            })
+            .collect();
+        Prefetcher {
+            stopped: stopped,
+            handlers: handlers,
+        }
+    }
+
+    /// Waits until every target is loaded, returns how many were.
+    pub fn join(self) -> usize {
+        self.handlers
+            .into_iter()
+            .map(|handler| handler.join().unwrap_or(0))
+            .sum()
+    }
+
+    /// Drops the targets not loaded yet, returns how many were.
+    pub fn stop(self) -> usize {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.join()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::Chain;
+    use crate::parallel_manager::ParallelManager;
+    use crate::shared_cache::SharedCache;
+    use crate::test_helpers;
+    use ethcore::factory::Factories;
+    use ethereum_types::U256;
+
+    #[test]
+    fn test_prefetch() {
+        let transactions = test_helpers::static_dep_txs(20, 40, false);
+        let state = test_helpers::funded_state(&transactions, U256::from(1));
+        let expected = test_helpers::sequential_root(&state, &transactions, &Chain::default());
+
+        let events: Vec<ExecutionEvent> = transactions
+            .iter()
+            .cloned()
+            .map(ExecutionEvent::Transact)
+            .collect();
+        let sender = transactions[0].sender();
+        let mut hints = HashMap::new();
+        hints.insert(sender, vec![H256::from(1)]);
+        let targets = targets(&events, &hints);
+        assert_eq!(targets[0], (sender, vec![H256::from(1)]));
+
+        let (root, state_db) = state.clone().drop();
+        let cache = SharedCache::default();
+        let prefetcher = Prefetcher::start(
+            cache.at(&state_db, &root, &Factories::default()),
+            3,
+            targets.clone(),
+        );
+        assert_eq!(prefetcher.join(), targets.len());
+        assert_eq!(cache.len(), targets.len());
+
+        let mut parallel_manager = ParallelManager::new(state);
+        parallel_manager.add_transactions(transactions);
+        parallel_manager.hint_storage(sender, vec![H256::from(1)]);
+        parallel_manager.set_prefetch_threads(2);
+        parallel_manager.add_engines(4);
+        test_helpers::run_block(&mut parallel_manager).unwrap();
+        assert_eq!(&expected, parallel_manager.state_root());
+    }
+}
@@ -0,0 +1,196 @@
+use common_types::block::Block;
+use common_types::header::Header;
+use common_types::transaction::{SignedTransaction, UnverifiedTransaction};
+use ethstore::ethkey;
+use rlp::{Decodable, DecoderError, Rlp};
+use std::sync::Arc;
+use std::thread;
+
+/// A decoded block whose transactions are ready to be added to a
+/// `ParallelManager`.
+#[derive(Clone, Debug)]
+pub struct PreparedBlock {
+    pub header: Header,
+    /// Transactions with a valid signature, in block order.
+    pub transactions: Vec<SignedTransaction>,
+    /// (index in the block, error) of the transactions whose sender could
+    /// not be recovered.
+    pub invalid: Vec<(usize, ethkey::Error)>,
+}
+
+impl PreparedBlock {
+    /// Recovers the senders of a decoded block on `threads` threads.
+    pub fn from_block(block: Block, threads: usize) -> PreparedBlock {
+        let recovered = recover_senders(block.transactions, threads);
+        PreparedBlock::from_recovered(block.header, recovered)
+    }
+
+    fn from_recovered(
+        header: Header,
+        recovered: Vec<Result<SignedTransaction, ethkey::Error>>,
+    ) -> PreparedBlock {
+        let mut transactions = vec![];
+        let mut invalid = vec![];
+        for (index, result) in recovered.into_iter().enumerate() {
+            match result {
+                Ok(tx) => transactions.push(tx),
+                Err(e) => invalid.push((index, e)),
+            }
+        }
+        PreparedBlock {
+            header: header,
+            transactions: transactions,
+            invalid: invalid,
+        }
+    }
+
+    /// Whether every transaction of the block has a valid signature, the
+    /// block can't be executed otherwise.
+    pub fn is_valid(&self) -> bool {
+        self.invalid.is_empty()
+    }
+}
+
+/// Decodes RLP encoded blocks and recovers the senders of all their
+/// transactions, both spread over `threads` threads. Returns the blocks in
+/// order, or the decoding error of each block which could not be decoded.
+pub fn prepare_blocks(
+    raw_blocks: Vec<Vec<u8>>,
+    threads: usize,
+) -> Vec<Result<PreparedBlock, DecoderError>> {
+    let blocks = decode_blocks(raw_blocks, threads);
+
+    // All transactions are recovered at once, so that a block with few
+    // transactions doesn't leave threads idle.
+    let mut headers = vec![];
+    let mut transactions = vec![];
+    for block in blocks {
+        match block {
+            Ok(block) => {
+                headers.push(Ok((block.header, block.transactions.len())));
+                transactions.extend(block.transactions);
+            }
+            Err(e) => headers.push(Err(e)),
+        }
+    }
+    let mut recovered = recover_senders(transactions, threads).into_iter();
+    headers
+        .into_iter()
+        .map(|header| {
+            header.map(|(header, count)| {
+                PreparedBlock::from_recovered(header, recovered.by_ref().take(count).collect())
+            })
+        })
+        .collect()
+}
+
+/// Decodes RLP encoded blocks on `threads` threads, in order.
+pub fn decode_blocks(raw_blocks: Vec<Vec<u8>>, threads: usize) -> Vec<Result<Block, DecoderError>> {
+    parallel_map(raw_blocks, threads, "decode", |bytes| {
+        Block::decode(&Rlp::new(&bytes))
+    })
+}
+
+/// Recovers the senders of the transactions on `threads` threads, in
+/// order.
+pub fn recover_senders(
+    transactions: Vec<UnverifiedTransaction>,
+    threads: usize,
+) -> Vec<Result<SignedTransaction, ethkey::Error>> {
+    parallel_map(transactions, threads, "recover", SignedTransaction::new)
+}
+
+/// Applies `f` to consecutive chunks of `items` on up to `threads` threads,
+/// keeping the order of the items.
+fn parallel_map<T, R, F>(items: Vec<T>, threads: usize, name: &str, f: F) -> Vec<R>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(T) -> R + Send + Sync + 'static,
+{
+    let chunk_size = (items.len() + threads.max(1) - 1) / threads.max(1);
+    if chunk_size == 0 {
+        return vec![];
+    }
+    let f = Arc::new(f);
+    let mut items = items.into_iter();
+    let mut handlers = vec![];
+    let mut number = 0;
+    loop {
+        let chunk: Vec<T> = items.by_ref().take(chunk_size).collect();
+        if chunk.is_empty() {
+            break;
+        }
+        let f = f.clone();
+        handlers.push(
+            thread::Builder::new()
+                .name(format!("{}{}", name.to_string(), &number.to_string()))
+                .spawn(move || chunk.into_iter().map(|item| f(item)).collect::<Vec<R>>())
+                .unwrap(),
+        );
+        number += 1;
+    }
+    handlers
+        .into_iter()
+        .flat_map(|handler| handler.join().unwrap())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::parallel_manager::ParallelManager;
+    use crate::test_helpers::{self, transfer};
+    use common_types::transaction::{Action, Transaction};
+    use ethereum_types::{Address, U256};
+    use ethstore::ethkey::{Generator, Random};
+
+    #[test]
+    fn test_prepare_blocks() {
+        let sender = Random.generate().unwrap();
+        let valid = transfer(&sender, Address::from(1), 21_000);
+        let invalid = Transaction {
+            action: Action::Call(Address::from(1)),
+            value: U256::from(1),
+            data: vec![],
+            gas: U256::from(21_000),
+            gas_price: U256::zero(),
+            nonce: U256::from(1),
+        }
+        .invalid_sign();
+        let block = Block {
+            header: Default::default(),
+            transactions: vec![(*valid).clone(), invalid],
+            uncles: vec![],
+        };
+
+        let raw_blocks = vec![block.rlp_bytes(), vec![0x01, 0x02], block.rlp_bytes()];
+        let prepared = prepare_blocks(raw_blocks, 2);
+        assert_eq!(prepared.len(), 3);
+        assert!(prepared[1].is_err());
+        for block in vec![&prepared[0], &prepared[2]] {
+            let block = block.as_ref().unwrap();
+            assert_eq!(block.transactions, vec![valid.clone()]);
+            assert_eq!(block.invalid.len(), 1);
+            assert_eq!(block.invalid[0].0, 1);
+            assert!(!block.is_valid());
+        }
+
+        // a block missing a transaction can't be executed
+        let mut parallel_manager = ParallelManager::new(test_helpers::get_temp_state());
+        match parallel_manager.add_prepared_block(prepared[0].as_ref().unwrap()) {
+            Err(Error::InvalidSignature(index, _)) => assert_eq!(index, 1),
+            _ => panic!("the block has an invalid signature"),
+        }
+        let block = PreparedBlock::from_block(
+            Block {
+                header: Default::default(),
+                transactions: vec![(*valid).clone()],
+                uncles: vec![],
+            },
+            2,
+        );
+        parallel_manager.add_prepared_block(&block).unwrap();
+    }
+}
@@ -0,0 +1,49 @@
+use common_types::receipt::{Receipt, TransactionOutcome};
+use ethereum_types::{Bloom, H256, U256};
+use rlp::Encodable;
+use triehash_ethereum::ordered_trie_root;
+use vm::EnvInfo;
+
+/// Receipt carrying only the gas used by its own transaction. Executors
+/// which do not see the whole block can't know the cumulative gas used,
+/// it is restored in block order by `cumulate`.
+pub fn own_gas_receipt(mut receipt: Receipt, env_info: &EnvInfo) -> Receipt {
+    receipt.gas_used = receipt.gas_used - env_info.gas_used;
+    receipt
+}
+
+/// Turns receipts of `own_gas_receipt`, in block order, into receipts with
+/// the cumulative gas used.
+pub fn cumulate(receipts: Vec<Receipt>) -> Vec<Receipt> {
+    let mut gas_used = U256::zero();
+    receipts
+        .into_iter()
+        .map(|mut receipt| {
+            gas_used = gas_used + receipt.gas_used;
+            receipt.gas_used = gas_used;
+            receipt
+        })
+        .collect()
+}
+
+/// Whether the receipt carries the state root after its transaction, which
+/// an engine computes on its own state only.
+pub fn has_intermediate_root(receipt: &Receipt) -> bool {
+    match receipt.outcome {
+        TransactionOutcome::StateRoot(_) => true,
+        _ => false,
+    }
+}
+
+pub fn receipts_root(receipts: &[Receipt]) -> H256 {
+    ordered_trie_root(receipts.iter().map(|receipt| receipt.rlp_bytes()))
+}
+
+pub fn log_bloom(receipts: &[Receipt]) -> Bloom {
+    receipts
+        .iter()
+        .fold(Bloom::default(), |mut bloom, receipt| {
+            bloom.accrue_bloom(&receipt.log_bloom);
+            bloom
+        })
+}
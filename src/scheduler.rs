@@ -0,0 +1,612 @@
+use crate::execution_engine::ExecutionEvent;
+use common_types::transaction::Action;
+use ethcore::executive::contract_address;
+use ethcore::open_state::State;
+use ethcore::open_state_db::StateDB;
+use ethereum_types::{Address, U256};
+use hashbrown::{HashMap, HashSet};
+use std::ops::Deref;
+use vm::CreateContractAddress;
+
+/// Engine picked for an event.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Assignment {
+    pub engine: usize,
+    /// (address, from engine) of the account caches to move to `engine`
+    /// before the event is executed.
+    pub migrations: Vec<(Address, usize)>,
+}
+
+/// Policy dispatching the events of a block to the engines.
+pub trait Scheduler: Send {
+    /// Starts a block run by `engines` engines.
+    fn reset(&mut self, engines: usize);
+
+    /// Starts a block keeping the accounts assigned to the engines by the
+    /// earlier blocks, see `ParallelManager::set_carry_over`.
+    fn retain(&mut self, engines: usize) {
+        self.reset(engines);
+    }
+
+    /// Looks at the whole block before any event is scheduled.
+    fn prepare(&mut self, _events: &[ExecutionEvent], _state: &State<StateDB>) {}
+
+    /// Picks the engine of the event at `index`, `None` if the event is not
+    /// bound to an engine, like an env change which every engine gets.
+    /// `state` is the state at the start of the block.
+    fn schedule(
+        &mut self,
+        index: usize,
+        event: &ExecutionEvent,
+        state: &State<StateDB>,
+    ) -> Option<Assignment>;
+
+    /// Engine the account is currently assigned to, if any.
+    fn owner(&self, addr: &Address) -> Option<usize>;
+
+    fn boxed_clone(&self) -> Box<dyn Scheduler>;
+}
+
+/// Where events without a dependency on any engine go.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Placement {
+    /// The next engine in turn.
+    RoundRobin,
+    /// The engine with the least gas queued. Only the gas limit of a
+    /// transaction is known before it is executed, so that is what counts.
+    GasWeighted,
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        Placement::RoundRobin
+    }
+}
+
+/// The default policy: an event goes to the engine already holding its
+/// sender or receiver, or is placed by `Placement` if neither was seen yet.
+/// If sender and receiver are on different engines, the sender's cache is
+/// migrated to the receiver's engine.
+#[derive(Clone, Debug, Default)]
+pub struct DependencyScheduler {
+    dependency_table: HashMap<Address, usize>,
+    best_thread: usize,
+    engines: usize,
+    placement: Placement,
+    // gas limit of the transactions queued on each engine
+    loads: Vec<U256>,
+    // accounts paired by an event, which move between engines together
+    links: HashMap<Address, Vec<Address>>,
+    // accounts pinned by the events of the block, the only ones the engines
+    // hold caches of
+    seen: HashSet<Address>,
+}
+
+impl DependencyScheduler {
+    pub fn with_placement(placement: Placement) -> DependencyScheduler {
+        DependencyScheduler {
+            placement: placement,
+            ..Default::default()
+        }
+    }
+
+    /// Gas queued on each engine in the current block.
+    pub fn loads(&self) -> &[U256] {
+        &self.loads
+    }
+
+    fn free_thread(&self) -> usize {
+        match self.placement {
+            Placement::RoundRobin => self.best_thread,
+            Placement::GasWeighted => (0..self.engines)
+                .min_by_key(|tid| self.loads[*tid])
+                .unwrap_or(0),
+        }
+    }
+
+    /// `addr` and every account on the same engine linked to it by the
+    /// events of the block so far.
+    fn related(&self, addr: &Address, tid: usize) -> Vec<Address> {
+        let mut related = vec![addr.clone()];
+        let mut i = 0;
+        while i < related.len() {
+            if let Some(links) = self.links.get(&related[i]) {
+                for link in links {
+                    if self.dependency_table.get(link) == Some(&tid) && !related.contains(link) {
+                        related.push(link.clone());
+                    }
+                }
+            }
+            i += 1;
+        }
+        related
+    }
+
+    fn exec_tid(&mut self, sender: &Address, to: &Address, gas: U256) -> Assignment {
+        let mut dependency_level = 0;
+        // dependency thread id.
+        let mut dependency_tid = [0, 0];
+        // address need to be insert to dependency table, possibly
+        // ethereum address of transaction sender and receiver.
+        let mut insert_addr = [sender.clone(), to.clone()];
+        let mut migrations = vec![];
+
+        // Find static dependency between threads, and count the
+        // dependency level.
+        for i in 0..2 {
+            match self.dependency_table.get(&insert_addr[i]) {
+                Some(tid) => {
+                    dependency_tid[i] = *tid;
+                    dependency_level = dependency_level + i + 1;
+                    insert_addr[i] = Address::zero();
+                }
+                None => (),
+            }
+        }
+        let mut exec_tid = self.free_thread();
+        if dependency_level == 1
+            || dependency_level == 2
+            || (dependency_level == 3 && dependency_tid[0] == dependency_tid[1])
+        {
+            // If single dependency
+            if dependency_level == 3 {
+                dependency_level = 2;
+            }
+            exec_tid = dependency_tid[dependency_level - 1];
+        } else if dependency_level == 3 {
+            // If double dependency, the caches of the sender and its related
+            // accounts move from sender_tid to to_tid. An account carried
+            // over from an earlier block and not pinned since is not in the
+            // engine's fresh state, it is only reassigned.
+            let drop_tid = dependency_tid[0];
+            exec_tid = dependency_tid[1];
+            for addr in self.related(sender, drop_tid) {
+                self.dependency_table.insert(addr, exec_tid);
+                if self.seen.contains(&addr) {
+                    migrations.push((addr, drop_tid));
+                }
+            }
+        }
+
+        // Update dependency table
+        for i in 0..2 {
+            if insert_addr[i] != Address::zero() {
+                self.dependency_table.insert(insert_addr[i], exec_tid);
+            }
+        }
+
+        if self.best_thread == exec_tid {
+            self.best_thread = (self.best_thread + 1) % self.engines;
+        }
+        self.loads[exec_tid] += gas;
+        self.seen.insert(*sender);
+        if *to != Address::zero() {
+            self.seen.insert(*to);
+            self.links.entry(*sender).or_default().push(*to);
+            self.links.entry(*to).or_default().push(*sender);
+        }
+
+        Assignment {
+            engine: exec_tid,
+            migrations: migrations,
+        }
+    }
+}
+
+impl Scheduler for DependencyScheduler {
+    fn reset(&mut self, engines: usize) {
+        self.dependency_table = HashMap::new();
+        self.best_thread = 0;
+        self.engines = engines;
+        self.loads = vec![U256::zero(); engines];
+        self.links = HashMap::new();
+        self.seen = HashSet::new();
+    }
+
+    fn retain(&mut self, engines: usize) {
+        let mut dependency_table = HashMap::new();
+        std::mem::swap(&mut dependency_table, &mut self.dependency_table);
+        self.reset(engines);
+        self.dependency_table = dependency_table
+            .into_iter()
+            .filter(|(_, tid)| *tid < engines)
+            .collect();
+    }
+
+    fn schedule(
+        &mut self,
+        _index: usize,
+        event: &ExecutionEvent,
+        state: &State<StateDB>,
+    ) -> Option<Assignment> {
+        pinned_accounts(event, state).map(|(sender, to, gas)| self.exec_tid(&sender, &to, gas))
+    }
+
+    fn owner(&self, addr: &Address) -> Option<usize> {
+        self.dependency_table.get(addr).cloned()
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Scheduler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Splits a block into the connected components of the accounts its events
+/// pin, and bin-packs whole components onto the engines by gas, heaviest
+/// first. Events of a component never leave its engine, so no account cache
+/// is migrated during the block.
+#[derive(Clone, Debug, Default)]
+pub struct PartitionScheduler {
+    engines: usize,
+    // engine of every pinned account of the block
+    partition: HashMap<Address, usize>,
+    // gas limit of the components packed on each engine
+    loads: Vec<U256>,
+}
+
+impl PartitionScheduler {
+    /// Gas packed on each engine in the current block.
+    pub fn loads(&self) -> &[U256] {
+        &self.loads
+    }
+}
+
+impl Scheduler for PartitionScheduler {
+    fn reset(&mut self, engines: usize) {
+        self.engines = engines;
+        self.partition = HashMap::new();
+        self.loads = vec![U256::zero(); engines];
+    }
+
+    fn prepare(&mut self, events: &[ExecutionEvent], state: &State<StateDB>) {
+        let mut components = DisjointSet::default();
+        let mut gas: HashMap<Address, U256> = HashMap::new();
+        for event in events {
+            if let Some((sender, to, event_gas)) = pinned_accounts(event, state) {
+                let root = if to == Address::zero() {
+                    components.find(sender)
+                } else {
+                    components.union(sender, to)
+                };
+                *gas.entry(root).or_insert_with(U256::zero) += event_gas;
+            }
+        }
+        // gas of each component, keyed by its final root
+        let mut totals: HashMap<Address, U256> = HashMap::new();
+        for (root, event_gas) in gas {
+            *totals
+                .entry(components.find(root))
+                .or_insert_with(U256::zero) += event_gas;
+        }
+        let mut totals: Vec<(Address, U256)> = totals.into_iter().collect();
+        totals.sort_by(|(a, a_gas), (b, b_gas)| b_gas.cmp(a_gas).then(a.cmp(b)));
+
+        let mut engine_of: HashMap<Address, usize> = HashMap::new();
+        for (root, total) in totals {
+            let engine = (0..self.engines)
+                .min_by_key(|tid| self.loads[*tid])
+                .unwrap_or(0);
+            self.loads[engine] += total;
+            engine_of.insert(root, engine);
+        }
+        for addr in components.addresses() {
+            let engine = engine_of[&components.find(addr)];
+            self.partition.insert(addr, engine);
+        }
+    }
+
+    fn schedule(
+        &mut self,
+        index: usize,
+        event: &ExecutionEvent,
+        state: &State<StateDB>,
+    ) -> Option<Assignment> {
+        pinned_accounts(event, state).map(|(sender, _, _)| Assignment {
+            // events not seen by `prepare` are spread in turn
+            engine: self
+                .partition
+                .get(&sender)
+                .cloned()
+                .unwrap_or(index % self.engines),
+            migrations: vec![],
+        })
+    }
+
+    fn owner(&self, addr: &Address) -> Option<usize> {
+        self.partition.get(addr).cloned()
+    }
+
+    fn boxed_clone(&self) -> Box<dyn Scheduler> {
+        Box::new(self.clone())
+    }
+}
+
+/// Union-find over addresses.
+#[derive(Default)]
+struct DisjointSet {
+    parents: HashMap<Address, Address>,
+}
+
+impl DisjointSet {
+    fn find(&mut self, addr: Address) -> Address {
+        let parent = *self.parents.entry(addr).or_insert(addr);
+        if parent == addr {
+            return addr;
+        }
+        let root = self.find(parent);
+        self.parents.insert(addr, root);
+        root
+    }
+
+    fn union(&mut self, a: Address, b: Address) -> Address {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parents.insert(b, a);
+        a
+    }
+
+    fn addresses(&self) -> Vec<Address> {
+        self.parents.keys().cloned().collect()
+    }
+}
+
+/// (sender, receiver, gas limit) of an event bound to an engine, the
+/// receiver is zero if it is not pinned.
+fn pinned_accounts(
+    event: &ExecutionEvent,
+    state: &State<StateDB>,
+) -> Option<(Address, Address, U256)> {
+    match event {
+        ExecutionEvent::Transact(tx) => {
+            let to = match tx.deref().deref().action {
+                // The would-be contract address, so later calls to it
+                // land on the deploying engine.
+                Action::Create => {
+                    contract_address(
+                        CreateContractAddress::FromSenderAndNonce,
+                        &tx.sender(),
+                        &tx.nonce,
+                        &tx.data,
+                    )
+                    .0
+                }
+                // Contracts called without value are not pinned to
+                // an engine, conflicts on their storage are checked
+                // per slot after execution.
+                Action::Call(addr) if tx.value.is_zero() && has_code(state, &addr) => {
+                    Address::zero()
+                }
+                Action::Call(addr) => addr,
+            };
+            Some((tx.sender(), to, tx.gas))
+        }
+        ExecutionEvent::AddBalance(addr, _) => Some((*addr, Address::zero(), U256::zero())),
+        _ => None,
+    }
+}
+
+fn has_code(state: &State<StateDB>, addr: &Address) -> bool {
+    match state.code_size(addr) {
+        Ok(Some(size)) => size > 0,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::Chain;
+    use crate::parallel_manager::ParallelManager;
+    use crate::test_helpers::{self, transfer};
+    use common_types::transaction::{SignedTransaction, Transaction};
+    use ethstore::ethkey::{Generator, Random};
+
+    #[test]
+    fn test_gas_weighted_placement() {
+        let state = test_helpers::get_temp_state();
+        // one heavy call followed by plain transfers, all independent
+        let events: Vec<ExecutionEvent> = (0..7)
+            .map(|i| {
+                let gas = if i == 0 { 8_000_000 } else { 21_000 };
+                let tx = transfer(&Random.generate().unwrap(), Address::random(), gas);
+                ExecutionEvent::Transact(tx)
+            })
+            .collect();
+
+        let mut scheduler = DependencyScheduler::with_placement(Placement::GasWeighted);
+        scheduler.reset(4);
+        let engines: Vec<usize> = events
+            .iter()
+            .enumerate()
+            .map(|(index, event)| scheduler.schedule(index, event, &state).unwrap().engine)
+            .collect();
+        assert_eq!(engines, vec![0, 1, 2, 3, 1, 2, 3]);
+        assert_eq!(scheduler.loads()[0], U256::from(8_000_000));
+
+        // round-robin comes back to the busy engine
+        let mut scheduler = DependencyScheduler::default();
+        scheduler.reset(4);
+        assert_eq!(scheduler.schedule(0, &events[0], &state).unwrap().engine, 0);
+        for (index, event) in events.iter().enumerate().skip(1).take(3) {
+            scheduler.schedule(index, event, &state);
+        }
+        assert_eq!(scheduler.schedule(4, &events[4], &state).unwrap().engine, 0);
+    }
+
+    #[test]
+    fn test_partition_scheduler() {
+        let transactions = test_helpers::static_dep_txs(50, 100, false);
+        let state = test_helpers::funded_state(&transactions, U256::from(1));
+        let events: Vec<ExecutionEvent> = transactions
+            .iter()
+            .cloned()
+            .map(ExecutionEvent::Transact)
+            .collect();
+
+        let mut scheduler = PartitionScheduler::default();
+        scheduler.reset(4);
+        scheduler.prepare(&events, &state);
+        for (index, event) in events.iter().enumerate() {
+            let assignment = scheduler.schedule(index, event, &state).unwrap();
+            assert!(assignment.migrations.is_empty());
+        }
+        // sender and receiver always share an engine
+        for tx in &transactions {
+            if let Action::Call(to) = tx.action {
+                assert_eq!(scheduler.owner(&tx.sender()), scheduler.owner(&to));
+            }
+        }
+
+        let expected = test_helpers::sequential_root(&state, &transactions, &Chain::default());
+        let mut parallel_manager = ParallelManager::new(state);
+        parallel_manager.set_scheduler(Box::new(PartitionScheduler::default()));
+        parallel_manager.add_engines(4);
+        parallel_manager.add_transactions(transactions);
+        test_helpers::run_block(&mut parallel_manager).unwrap();
+        assert_eq!(&expected, parallel_manager.state_root());
+    }
+
+    #[test]
+    fn test_create_then_call() {
+        let (creator, caller, other) = (
+            Random.generate().unwrap(),
+            Random.generate().unwrap(),
+            Random.generate().unwrap(),
+        );
+        let create = Transaction {
+            action: Action::Create,
+            value: U256::zero(),
+            data: vec![],
+            gas: U256::from(100_000),
+            gas_price: U256::zero(),
+            nonce: U256::zero(),
+        }
+        .sign(creator.secret(), None);
+        let (created, _) = contract_address(
+            CreateContractAddress::FromSenderAndNonce,
+            &creator.address(),
+            &U256::zero(),
+            &[],
+        );
+        let transactions = vec![
+            create,
+            transfer(&other, Address::random(), 21_000),
+            // the new contract has no code at the start of the block
+            transfer(&caller, created, 21_000),
+        ];
+        let state = test_helpers::funded_state(&transactions, U256::from(1));
+        let events: Vec<ExecutionEvent> = transactions
+            .iter()
+            .cloned()
+            .map(ExecutionEvent::Transact)
+            .collect();
+
+        let mut scheduler = DependencyScheduler::default();
+        scheduler.reset(2);
+        let assignments: Vec<_> = events
+            .iter()
+            .enumerate()
+            .map(|(index, event)| scheduler.schedule(index, event, &state).unwrap())
+            .collect();
+        assert_eq!(assignments[2].engine, assignments[0].engine);
+        assert!(assignments.iter().all(|a| a.migrations.is_empty()));
+        assert_eq!(scheduler.owner(&created), Some(assignments[0].engine));
+
+        let expected = test_helpers::sequential_root(&state, &transactions, &Chain::default());
+        let mut parallel_manager = ParallelManager::new(state);
+        parallel_manager.add_engines(2);
+        parallel_manager.add_transactions(transactions);
+        assert!(!test_helpers::run_block(&mut parallel_manager).unwrap().race);
+        assert_eq!(&expected, parallel_manager.state_root());
+    }
+
+    #[test]
+    fn test_related_migration() {
+        let state = test_helpers::get_temp_state();
+        let (a, c) = (Random.generate().unwrap(), Random.generate().unwrap());
+        let (b, d) = (Address::random(), Address::random());
+        let events: Vec<ExecutionEvent> = vec![
+            transfer(&a, b, 21_000),
+            transfer(&c, d, 21_000),
+            transfer(&a, c.address(), 21_000),
+        ]
+        .into_iter()
+        .map(ExecutionEvent::Transact)
+        .collect();
+
+        let mut scheduler = DependencyScheduler::default();
+        scheduler.reset(2);
+        scheduler.schedule(0, &events[0], &state);
+        scheduler.schedule(1, &events[1], &state);
+        // `a` moves to `c`'s engine along with `b` it paid before
+        let mut assignment = scheduler.schedule(2, &events[2], &state).unwrap();
+        assignment.migrations.sort();
+        let mut expected = vec![(a.address(), 0), (b, 0)];
+        expected.sort();
+        assert_eq!(assignment.engine, 1);
+        assert_eq!(assignment.migrations, expected);
+        assert_eq!(scheduler.owner(&a.address()), Some(1));
+        assert_eq!(scheduler.owner(&b), Some(1));
+        assert_eq!(scheduler.owner(&d), Some(1));
+    }
+
+    #[test]
+    fn test_carried_migration() {
+        let (a, c, e) = (
+            Random.generate().unwrap(),
+            Random.generate().unwrap(),
+            Random.generate().unwrap(),
+        );
+        let (b, d) = (Address::random(), Address::random());
+        let first = vec![transfer(&a, b, 21_000), transfer(&c, d, 21_000)];
+        let second = vec![
+            transfer(&e, b, 21_000),
+            // `c` was left on engine 1 by the first block
+            Transaction {
+                action: Action::Call(a.address()),
+                value: U256::from(1),
+                data: vec![],
+                gas: U256::from(21_000),
+                gas_price: U256::zero(),
+                nonce: U256::from(1),
+            }
+            .sign(c.secret(), None),
+        ];
+        let all: Vec<_> = first.iter().chain(&second).cloned().collect();
+        let state = test_helpers::funded_state(&all, U256::from(10));
+        let events = |transactions: &Vec<SignedTransaction>| -> Vec<ExecutionEvent> {
+            transactions
+                .iter()
+                .cloned()
+                .map(ExecutionEvent::Transact)
+                .collect()
+        };
+
+        let mut scheduler = DependencyScheduler::default();
+        scheduler.reset(2);
+        for (index, event) in events(&first).iter().enumerate() {
+            scheduler.schedule(index, event, &state);
+        }
+        scheduler.retain(2);
+        assert_eq!(scheduler.owner(&c.address()), Some(1));
+        let assignments: Vec<_> = events(&second)
+            .iter()
+            .enumerate()
+            .map(|(index, event)| scheduler.schedule(index, event, &state).unwrap())
+            .collect();
+        // moved to `a`'s engine without a cache
+        assert_eq!(assignments[1].engine, 0);
+        assert!(assignments[1].migrations.is_empty());
+        assert_eq!(scheduler.owner(&c.address()), Some(0));
+
+        let expected = test_helpers::sequential_root(&state, &all, &Chain::default());
+        let mut parallel_manager = ParallelManager::new(state);
+        parallel_manager.set_carry_over(true);
+        for transactions in vec![first, second] {
+            parallel_manager.set_events(events(&transactions));
+            parallel_manager.add_engines(2);
+            test_helpers::run_block(&mut parallel_manager).unwrap();
+        }
+        assert_eq!(&expected, parallel_manager.state_root());
+        parallel_manager.shutdown().unwrap();
+    }
+}
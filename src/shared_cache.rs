@@ -0,0 +1,232 @@
+use crate::execution_engine::ExecutionEvent;
+use common_types::transaction::Action;
+use ethcore::error::Error as EthcoreError;
+use ethcore::factory::Factories;
+use ethcore::open_state::{Account, AccountEntry, State};
+use ethcore::open_state_db::StateDB;
+use ethereum_types::{Address, H256};
+use hashbrown::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use trie_db::Trie;
+
+/// Number of parts of the cache locked on their own, picked by the first
+/// byte of the address.
+const SHARDS: usize = 16;
+
+// decoded accounts by (root, address), `None` if the account doesn't exist
+type Shard = Mutex<HashMap<(H256, Address), Option<Account>>>;
+
+/// Accounts decoded under a state root, with their code and the storage
+/// slots read so far, so that the engines, the secure engine and the
+/// prefetcher of one manager read and decode a hot account once per block
+/// instead of once per engine.
+///
+/// Entries are keyed by the root they were read under, so a cached account
+/// can't be stale. Each manager owns its cache: `refresh` drops the
+/// entries of every other root.
+#[derive(Clone)]
+pub struct SharedCache {
+    shards: Arc<Vec<Shard>>,
+}
+
+impl Default for SharedCache {
+    fn default() -> Self {
+        SharedCache {
+            shards: Arc::new((0..SHARDS).map(|_| Mutex::new(HashMap::new())).collect()),
+        }
+    }
+}
+
+impl SharedCache {
+    /// Handle reading the accounts at `root` through the cache, and from
+    /// `state_db` on a miss.
+    pub fn at(&self, state_db: &StateDB, root: &H256, factories: &Factories) -> BlockCache {
+        BlockCache {
+            cache: self.clone(),
+            root: root.clone(),
+            state_db: state_db.boxed_clone(),
+            factories: factories.clone(),
+        }
+    }
+
+    /// Drops the accounts read under another root.
+    pub fn refresh(&self, root: &H256) {
+        for shard in self.shards.iter() {
+            shard.lock().unwrap().retain(|(r, _), _| r == root);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().len())
+            .sum()
+    }
+
+    fn shard(&self, addr: &Address) -> &Shard {
+        &self.shards[addr[0] as usize % SHARDS]
+    }
+}
+
+/// Reads of one block through a `SharedCache`. Each clone reads from its
+/// own copy of the database, so that clones can be handed to other threads.
+pub struct BlockCache {
+    cache: SharedCache,
+    root: H256,
+    state_db: StateDB,
+    factories: Factories,
+}
+
+impl Clone for BlockCache {
+    fn clone(&self) -> Self {
+        BlockCache {
+            cache: self.cache.clone(),
+            root: self.root.clone(),
+            state_db: self.state_db.boxed_clone(),
+            factories: self.factories.clone(),
+        }
+    }
+}
+
+impl BlockCache {
+    /// Account at the root with its code and cached storage, decoded on
+    /// the first request only. The shard is not locked while the account
+    /// is read from the database.
+    pub fn account(&self, addr: &Address) -> Result<Option<Account>, EthcoreError> {
+        let shard = self.cache.shard(addr);
+        let key = (self.root.clone(), *addr);
+        if let Some(account) = shard.lock().unwrap().get(&key) {
+            return Ok(account.as_ref().map(Account::clone_all));
+        }
+        let account = self.load(addr)?;
+        let mut accounts = shard.lock().unwrap();
+        // another thread may have loaded it meanwhile
+        let account = accounts.entry(key).or_insert(account);
+        Ok(account.as_ref().map(Account::clone_all))
+    }
+
+    /// Storage slot at the root, kept in the storage cache of the cached
+    /// account so that it is handed out along with the account.
+    pub fn storage_at(&self, addr: &Address, key: &H256) -> Result<H256, EthcoreError> {
+        if self.account(addr)?.is_none() {
+            return Ok(H256::zero());
+        }
+        let accounts = self.cache.shard(addr).lock().unwrap();
+        match accounts.get(&(self.root.clone(), *addr)) {
+            Some(Some(account)) => {
+                if let Some(value) = account.cached_storage_at(key) {
+                    return Ok(value);
+                }
+                let account_db = self
+                    .factories
+                    .accountdb
+                    .readonly(self.state_db.as_hash_db(), account.address_hash(addr));
+                Ok(account.storage_at(account_db.as_hash_db(), key)?)
+            }
+            _ => Ok(H256::zero()),
+        }
+    }
+
+    /// Loads the accounts into a state at the root which hasn't read them
+    /// yet. Entries the state already holds are replaced, they must not
+    /// have been changed.
+    pub fn warm(&self, state: &mut State<StateDB>, addrs: &[Address]) {
+        for addr in addrs {
+            // A failed read is left to the state to report.
+            if let Ok(account) = self.account(addr) {
+                state.insert_cache(addr, AccountEntry::new_clean(account));
+            }
+        }
+    }
+
+    fn load(&self, addr: &Address) -> Result<Option<Account>, EthcoreError> {
+        let db = self.state_db.as_hash_db();
+        let trie = self.factories.trie.readonly(db, &self.root)?;
+        let mut account = match trie.get(addr)? {
+            Some(rlp) => Account::from_rlp(&rlp)?,
+            None => return Ok(None),
+        };
+        let account_db = self
+            .factories
+            .accountdb
+            .readonly(db, account.address_hash(addr));
+        account.cache_code(account_db.as_hash_db());
+        Ok(Some(account))
+    }
+}
+
+/// Senders and receivers of the transactions, which an engine loads from
+/// the cache before executing one.
+pub fn transaction_accounts(event: &ExecutionEvent) -> Vec<Address> {
+    match event {
+        ExecutionEvent::Transact(tx) => match tx.deref().deref().action {
+            Action::Call(to) => vec![tx.sender(), to],
+            // the created account doesn't exist yet
+            Action::Create => vec![tx.sender()],
+        },
+        ExecutionEvent::AddBalance(addr, _) => vec![*addr],
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helpers;
+    use ethcore::open_state::CleanupMode;
+    use ethereum_types::U256;
+
+    #[test]
+    fn test_shared_cache() {
+        let mut state = test_helpers::get_temp_state();
+        let a = Address::from(1);
+        state
+            .add_balance(&a, &U256::from(10), CleanupMode::NoEmpty)
+            .unwrap();
+        state.set_storage(&a, H256::from(1), H256::from(2)).unwrap();
+        state.commit().unwrap();
+        let (root, state_db) = state.drop();
+        let cache = SharedCache::default();
+        let first = cache.at(&state_db, &root, &Factories::default());
+        assert_eq!(cache.len(), 0);
+
+        // decoded once, handed out to every clone
+        let account = first.account(&a).unwrap().unwrap();
+        assert_eq!(account.balance(), &U256::from(10));
+        assert_eq!(cache.len(), 1);
+        let second = first.clone();
+        assert_eq!(
+            second.account(&a).unwrap().unwrap().balance(),
+            &U256::from(10)
+        );
+        assert!(second.account(&Address::from(2)).unwrap().is_none());
+        assert_eq!(cache.len(), 2);
+
+        // storage read by one is cached in the account of the other
+        assert_eq!(first.storage_at(&a, &H256::from(1)).unwrap(), H256::from(2));
+        let account = second.account(&a).unwrap().unwrap();
+        assert_eq!(
+            account.cached_storage_at(&H256::from(1)),
+            Some(H256::from(2))
+        );
+
+        // a state reads the warmed accounts
+        let mut state = State::from_existing(
+            state_db.boxed_clone(),
+            root,
+            U256::zero(),
+            Factories::default(),
+        )
+        .unwrap();
+        second.warm(&mut state, &[a]);
+        assert_eq!(state.balance(&a).unwrap(), U256::from(10));
+        assert_eq!(state.storage_at(&a, &H256::from(1)).unwrap(), H256::from(2));
+
+        // kept for the same root, dropped for the next one
+        cache.refresh(&root);
+        assert_eq!(cache.len(), 2);
+        cache.refresh(&H256::from(1));
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -33,7 +33,11 @@ pub fn static_dep_txs(
                 receivers.push(result[1].address());
             }
             let txs = transfer_txs(&senders, &receivers);
-            save_transactions(&txs, &path);
+            // only shared through the file when asked to, tests running in
+            // parallel would race on it
+            if auto_load {
+                save_transactions(&txs, &path);
+            }
             txs
         }
     };
@@ -90,6 +94,19 @@ pub fn transfer_txs(
     result
 }
 
+/// Signed transfer of 1 wei with nonce 0 and no gas price.
+pub fn transfer(sender: &KeyPair, to: Address, gas: u64) -> SignedTransaction {
+    Transaction {
+        action: Action::Call(to),
+        value: U256::from(1),
+        data: vec![],
+        gas: U256::from(gas),
+        gas_price: U256::zero(),
+        nonce: U256::zero(),
+    }
+    .sign(sender.secret(), None)
+}
+
 pub fn save_transactions(transactions: &Vec<SignedTransaction>, path: &str) {
     let mut writer = BufWriter::new(File::create(path).unwrap());
     let mut rlp_transactions = vec![];
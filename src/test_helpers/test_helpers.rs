@@ -1,7 +1,12 @@
+use crate::chain::Chain;
+use crate::error::Error;
+use crate::execution_engine::sequential_exec;
+use crate::parallel_manager::{ConflictReport, ParallelManager};
 use common_types::block::Block;
 use common_types::header::Header;
+use common_types::transaction::SignedTransaction;
 use ethcore::client::ClientConfig;
-use ethcore::open_state::State;
+use ethcore::open_state::{CleanupMode, State};
 use ethcore::open_state_db::StateDB;
 use ethcore::test_helpers::new_db;
 use ethcore_blockchain::BlockChainDB;
@@ -35,6 +40,45 @@ pub fn get_temp_state() -> State<StateDB> {
     State::new(journal_db, U256::from(0), Default::default())
 }
 
+/// Returns temp state in which every sender of `transactions` has
+/// `balance`, committed
+pub fn funded_state(transactions: &[SignedTransaction], balance: U256) -> State<StateDB> {
+    let mut state = get_temp_state();
+    for tx in transactions {
+        state
+            .add_balance(&tx.sender(), &balance, CleanupMode::NoEmpty)
+            .unwrap();
+    }
+    state.commit().unwrap();
+    state
+}
+
+/// Root of `state` after applying `transactions` in order
+pub fn sequential_root(
+    state: &State<StateDB>,
+    transactions: &Vec<SignedTransaction>,
+    chain: &Chain,
+) -> H256 {
+    let mut state = state.clone();
+    sequential_exec(&mut state, transactions, chain).unwrap();
+    state.commit().unwrap();
+    state.root().clone()
+}
+
+/// Runs the events of the manager on its engines and applies the block,
+/// selectively if the engines raced
+pub fn run_block(parallel_manager: &mut ParallelManager) -> Result<ConflictReport, Error> {
+    parallel_manager.clone_to_secure();
+    parallel_manager.consume()?;
+    let report = parallel_manager.stop()?;
+    if report.race {
+        parallel_manager.apply_selective()?;
+    } else {
+        parallel_manager.apply_engines()?;
+    }
+    Ok(report)
+}
+
 struct AppDB {
     key_value: Arc<KeyValueDB>,
     blooms: blooms_db::Database,
@@ -96,6 +140,15 @@ pub fn open_database(db_path: &str) -> Arc<BlockChainDB> {
 }
 
 pub fn read_blocks(dir: &str, from: usize, to: usize) -> Vec<Block> {
+    read_raw_blocks(dir, from, to)
+        .iter()
+        .map(|bytes| Block::decode(&Rlp::new(bytes)).unwrap())
+        .collect()
+}
+
+/// RLP encoded blocks `from` to `to`, to be decoded by
+/// `preparation::prepare_blocks`.
+pub fn read_raw_blocks(dir: &str, from: usize, to: usize) -> Vec<Vec<u8>> {
     let mut instream = fs::File::open(&dir)
         .map_err(|_| format!("Cannot open given file: {}", dir))
         .unwrap();
@@ -133,9 +186,7 @@ pub fn read_blocks(dir: &str, from: usize, to: usize) -> Vec<Block> {
             .unwrap();
 
         if i >= from - 1 {
-            let raw_block = Rlp::new(&bytes);
-            let block = Block::decode(&raw_block).unwrap();
-            blocks.push(block);
+            blocks.push(bytes);
         }
     }
     blocks
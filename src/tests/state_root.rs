@@ -1,4 +1,5 @@
 use crate::parallel_manager::ParallelManager;
+use crate::preparation;
 use crate::reward::Reward;
 use crate::test_helpers;
 use common_types::transaction::SignedTransaction;
@@ -20,7 +21,7 @@ fn reproduce_7840001_state_root_parallel() {
         H256::from("0xa7ca2c04e692960dac04909b3212baf12df7666efac68afad4646b3205a32c91");
 
     let state_db = test_helpers::open_state_db(db_dir);
-    let blocks = &test_helpers::read_blocks(block_dir, 1, n);
+    let blocks = preparation::prepare_blocks(test_helpers::read_raw_blocks(block_dir, 1, n), 4);
     let rewards = &Reward::from_file(reward_dir, 1, n);
     let mut last_hashes = VecDeque::from(test_helpers::load_last_hashes(last_hashes_dir));
     last_hashes.pop_front();
@@ -32,28 +33,25 @@ fn reproduce_7840001_state_root_parallel() {
 
     let mut n_race = 0;
     for i in 0..n {
-        let mut env_info = test_helpers::header_to_envinfo(&blocks[i].header);
+        let block = blocks[i].as_ref().unwrap();
+        let mut env_info = test_helpers::header_to_envinfo(&block.header);
         println!("Processing block #{}", env_info.number);
-        last_hashes.push_front(blocks[i].header.parent_hash().clone());
+        last_hashes.push_front(block.header.parent_hash().clone());
         last_hashes.pop_back();
         env_info.last_hashes = Arc::new(last_hashes.clone().into());
         let mut parallel_manager = ParallelManager::new(state.clone());
-        let mut txs = vec![];
-        for utx in &blocks[i].transactions {
-            txs.push(SignedTransaction::new(utx.clone()).unwrap());
-        }
         parallel_manager.add_engines(3);
         parallel_manager.add_env_info(env_info.clone());
-        parallel_manager.add_transactions(txs);
+        parallel_manager.add_prepared_block(block).unwrap();
         parallel_manager.add_reward(&rewards[i]);
         parallel_manager.clone_to_secure();
-        parallel_manager.consume();
-        let race = parallel_manager.stop();
+        parallel_manager.consume().unwrap();
+        let race = parallel_manager.stop().unwrap().race;
         if race {
             n_race += 1;
-            parallel_manager.apply_secure();
+            parallel_manager.apply_selective().unwrap();
         } else {
-            parallel_manager.apply_engines();
+            parallel_manager.apply_engines().unwrap();
         }
         state = parallel_manager.drop();
         println!("{:?}, {}", state.root(), race);